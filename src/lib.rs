@@ -0,0 +1,8734 @@
+//! Translates gBridge switch commands into RF codes and publishes them to one or more target
+//! brokers. The binary only calls [`cli`], the translation can be used on its own too:
+//!
+//! ```
+//! use gbridge_bridge::{handle_publish, prepare_switch_configs, Config};
+//! use std::collections::HashMap;
+//!
+//! let mut config: Config = toml::from_str(include_str!("../config/config.toml.example"))?;
+//! let switches = prepare_switch_configs(std::mem::take(&mut config.switches))?;
+//! let translated = handle_publish(
+//!     "gBridge/u1/d2777/onoff",
+//!     b"1",
+//!     config.switch_name_segment,
+//!     &switches,
+//!     &config.target_topic,
+//!     &config.topic_rules(),
+//!     &HashMap::new(),
+//! )?;
+//! assert_eq!(translated.translation().unwrap().codes, vec!["FFFFFFFF0001"]);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use anyhow::{Context, Error};
+use rand::Rng;
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS, Request,
+    StateError, SubAck, SubscribeReturnCodes,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct MQTTConnectionConfig {
+    host: String,
+    user: String,
+    /// Can be left out when it comes from `GBRIDGE_<NAME>_PASSWORD`, see `apply_env_overrides`.
+    #[serde(default)]
+    password: String,
+    /// PEM bundle used to verify the broker, defaults to `DEFAULT_CA_PATH`.
+    ca_path: Option<String>,
+    #[serde(default = "default_tls")]
+    tls: bool,
+    /// Defaults to 8883 with TLS and 1883 without.
+    port: Option<u16>,
+    /// Seconds between PINGREQs on an idle connection, at least 5. Defaults to 5.
+    keep_alive_secs: Option<u16>,
+    /// Seconds to wait for the TCP/TLS connection and the CONNACK before retrying. Defaults to 10.
+    connect_timeout_secs: Option<u64>,
+    /// PEM client certificate and RSA key for brokers requiring mutual TLS. Set both or neither.
+    client_cert_path: Option<String>,
+    client_key_path: Option<String>,
+    /// Defaults to `source`/`target`. Brokers drop an existing connection when another client
+    /// connects with the same id, so two bridges sharing a broker need distinct ids or they will
+    /// keep kicking each other off.
+    client_id: Option<String>,
+    /// With `false` the broker keeps our subscriptions and queues QoS 1/2 messages while we're
+    /// disconnected. The session belongs to the client id, so it needs one no other client uses,
+    /// or the two take over each other's session. Defaults to `true`.
+    clean_session: Option<bool>,
+    /// Capacity of the client's request channel, i.e. how many publishes/subscribes can queue up
+    /// before callers wait. Smaller saves memory on constrained devices.
+    mqtt_cap: Option<usize>,
+    /// Name the broker certificate must be valid for, when it differs from `host`, e.g. behind a
+    /// load balancer. rumqttc 0.1 still sends `host` as SNI, only the verification changes.
+    tls_server_name: Option<String>,
+    /// HTTP CONNECT proxy like `proxy.local:3128`. Not supported yet: rumqttc 0.1 opens its own
+    /// TCP connection and has no way to hand it a tunnelled stream, so setting this is an error
+    /// rather than silently connecting directly.
+    proxy: Option<String>,
+    /// MQTT protocol version, `3` for 3.1.1 (the default) or `5`. rumqttc 0.1 doesn't speak v5,
+    /// so asking for it fails validation instead of quietly connecting with 3.1.1.
+    mqtt_version: Option<u8>,
+    /// User properties added to every publish on this connection. A v5 feature, so setting them
+    /// fails validation as well.
+    user_properties: Option<HashMap<String, String>>,
+    /// `tcp` (plain or with `tls`) is the only one that works, rumqttc 0.1 has no websocket
+    /// transport. `ws` and `wss` are accepted so asking for them fails validation.
+    #[serde(default)]
+    transport: MqttTransport,
+    /// Path of the websocket endpoint, e.g. `/mqtt`.
+    ws_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MqttTransport {
+    #[default]
+    Tcp,
+    Ws,
+    Wss,
+}
+
+impl MqttTransport {
+    fn as_str(self) -> &'static str {
+        match self {
+            MqttTransport::Tcp => "tcp",
+            MqttTransport::Ws => "ws",
+            MqttTransport::Wss => "wss",
+        }
+    }
+}
+
+impl MQTTConnectionConfig {
+    fn port(&self) -> u16 {
+        self.port
+            .unwrap_or(if self.tls { TLS_PORT } else { PLAINTEXT_PORT })
+    }
+
+    fn mqtt_cap(&self) -> usize {
+        self.mqtt_cap.unwrap_or(DEFAULT_MQTT_CAP)
+    }
+
+    /// Let `GBRIDGE_<NAME>_USER` and `GBRIDGE_<NAME>_PASSWORD` override the credentials from the
+    /// config file. A password must come from one of the two.
+    fn apply_env_overrides<F>(&mut self, name: &str, lookup: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let prefix = format!("GBRIDGE_{}", name.to_uppercase());
+        if let Some(user) = lookup(&format!("{}_USER", prefix)) {
+            self.user = user;
+        }
+        let password_var = format!("{}_PASSWORD", prefix);
+        if let Some(password) = lookup(&password_var) {
+            self.password = password;
+        }
+        if self.password.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No {} password configured, set it in the config file or via {}.",
+                name,
+                password_var
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_tls() -> bool {
+    true
+}
+
+fn default_warn_on_short_topic() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(try_from = "RawSwitchConfig")]
+pub struct SwitchConfig {
+    pub name: String,
+    kind: SwitchKind,
+    /// Publish this switch's codes here instead of the global `target_topic`.
+    target_topic: Option<String>,
+    /// Set for `match = "glob"` switches, whose name is then a pattern like `livingroom_*`.
+    pattern: Option<NamePattern>,
+    /// Commands arriving within this long of the last one sent for the switch are dropped.
+    debounce: Option<Duration>,
+    /// RF protocol number and pulse length for 433MHz transmitters. With either set the code is
+    /// sent as JSON along with them.
+    protocol: Option<u32>,
+    pulselength: Option<u32>,
+    /// Overrides `target_retain` for this switch.
+    retain: Option<bool>,
+    /// Send the off code for on and the other way round, for switches wired backwards. Needs an
+    /// off code.
+    invert: bool,
+    /// Overrides `target_qos` for this switch, e.g. QoS 2 for a heater.
+    qos: Option<QoS>,
+    /// Pause between the codes of an `on`/`off` list, defaults to `DEFAULT_CODE_GAP`.
+    code_gap: Option<Duration>,
+}
+
+/// Compiled glob of a switch name, compared by its pattern.
+#[derive(Clone, Debug)]
+struct NamePattern(globset::GlobMatcher);
+
+impl PartialEq for NamePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.glob() == other.0.glob()
+    }
+}
+
+impl Eq for NamePattern {}
+
+#[derive(Debug, PartialEq)]
+enum SwitchKind {
+    /// Plain on/off switch, the default when no `type` is given. Momentary switches that only
+    /// have an on action leave `off` out, off payloads are then ignored. Each action is one or
+    /// more codes sent in order, for remotes that need a code repeated or a pair of them.
+    OnOff {
+        on: Vec<String>,
+        off: Option<Vec<String>>,
+    },
+    /// Takes a brightness of `0`-`100` and sends the code of the highest level whose `min` is
+    /// not above it. Levels are kept sorted by `min`.
+    Dimmer { levels: Vec<DimmerLevel> },
+    /// Takes a sensor reading and sends `on` once it rises above `on_above` and `off` once it
+    /// falls below `off_below`. Readings in between change nothing, so a value hovering around
+    /// the setpoint doesn't keep switching.
+    Threshold {
+        on: Vec<String>,
+        off: Vec<String>,
+        on_above: f64,
+        off_below: f64,
+    },
+    /// Tries each rule's regex against the payload in order and sends the code of the first
+    /// that matches, for devices like `set:ON` that put the state somewhere in the payload.
+    Regex { rules: Vec<PayloadRule> },
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct DimmerLevel {
+    min: u8,
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPayloadRule {
+    regex: String,
+    code: String,
+}
+
+/// A `type = "regex"` rule, compiled once when the config is loaded.
+#[derive(Debug, PartialEq)]
+struct PayloadRule {
+    regex: PayloadRegex,
+    code: String,
+}
+
+/// Compiled payload regex, compared by its source.
+#[derive(Clone, Debug)]
+struct PayloadRegex(regex::Regex);
+
+impl PartialEq for PayloadRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// The code of the first rule matching `payload`.
+fn match_payload_rules<'a>(rules: &'a [PayloadRule], payload: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|rule| rule.regex.0.is_match(payload))
+        .map(|rule| rule.code.as_str())
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum SwitchType {
+    #[default]
+    OnOff,
+    Dimmer,
+    Threshold,
+    Regex,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum MatchMode {
+    #[default]
+    Exact,
+    Glob,
+}
+
+/// The flat on-disk shape of a `[[switches]]` entry, so plain switches don't need a `type`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSwitchConfig {
+    name: String,
+    #[serde(default, rename = "type")]
+    switch_type: SwitchType,
+    #[serde(default, deserialize_with = "optional_one_or_many")]
+    on: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "optional_one_or_many")]
+    off: Option<Vec<String>>,
+    #[serde(default)]
+    levels: Vec<DimmerLevel>,
+    on_above: Option<f64>,
+    off_below: Option<f64>,
+    #[serde(default)]
+    rules: Vec<RawPayloadRule>,
+    target_topic: Option<String>,
+    #[serde(default, rename = "match")]
+    match_mode: MatchMode,
+    debounce_ms: Option<u64>,
+    protocol: Option<u32>,
+    pulselength: Option<u32>,
+    retain: Option<bool>,
+    #[serde(default)]
+    invert: bool,
+    #[serde(default, deserialize_with = "deserialize_optional_qos")]
+    qos: Option<QoS>,
+    code_gap_ms: Option<u64>,
+}
+
+impl TryFrom<RawSwitchConfig> for SwitchConfig {
+    type Error = String;
+
+    fn try_from(mut raw: RawSwitchConfig) -> Result<Self, Self::Error> {
+        raw.name = raw.name.trim().to_string();
+        let kind = match raw.switch_type {
+            SwitchType::OnOff => match (raw.on, raw.off) {
+                (None, _) => return Err(format!("switch {} needs an `on` code", raw.name)),
+                (Some(codes), _) | (_, Some(codes)) if codes.is_empty() => {
+                    return Err(format!("switch {} has an empty list of codes", raw.name))
+                }
+                // Inverted, "on" sends the off code, so without one it could never be switched.
+                (Some(_), None) if raw.invert => {
+                    return Err(format!(
+                        "switch {} can't be inverted without an `off` code",
+                        raw.name
+                    ))
+                }
+                (Some(on), off) => SwitchKind::OnOff { on, off },
+            },
+            SwitchType::Dimmer => {
+                if raw.levels.is_empty() {
+                    return Err(format!("dimmer {} needs at least one level", raw.name));
+                }
+                let mut levels = raw.levels;
+                levels.sort_by_key(|l| l.min);
+                SwitchKind::Dimmer { levels }
+            }
+            SwitchType::Threshold => match (raw.on, raw.off, raw.on_above, raw.off_below) {
+                (Some(on), Some(off), Some(on_above), Some(off_below)) => {
+                    if on.is_empty() || off.is_empty() {
+                        return Err(format!("switch {} has an empty list of codes", raw.name));
+                    }
+                    if !off_below.is_finite() || !on_above.is_finite() || off_below > on_above {
+                        return Err(format!(
+                            "threshold {} needs off_below {} to be at most on_above {}",
+                            raw.name, off_below, on_above
+                        ));
+                    }
+                    SwitchKind::Threshold {
+                        on,
+                        off,
+                        on_above,
+                        off_below,
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "threshold {} needs `on`, `off`, `on_above` and `off_below`",
+                        raw.name
+                    ))
+                }
+            },
+            SwitchType::Regex => {
+                if raw.rules.is_empty() {
+                    return Err(format!("switch {} needs at least one rule", raw.name));
+                }
+                let name = &raw.name;
+                let rules = std::mem::take(&mut raw.rules)
+                    .into_iter()
+                    .map(|rule| {
+                        let regex = regex::Regex::new(&rule.regex)
+                            .map_err(|e| format!("switch {} has an invalid regex: {}", name, e))?;
+                        Ok(PayloadRule {
+                            regex: PayloadRegex(regex),
+                            code: rule.code,
+                        })
+                    })
+                    .collect::<Result<_, String>>()?;
+                SwitchKind::Regex { rules }
+            }
+        };
+        let pattern = match raw.match_mode {
+            MatchMode::Exact => None,
+            MatchMode::Glob => {
+                let name = &raw.name;
+                let glob = globset::Glob::new(name)
+                    .map_err(|e| format!("switch {} is not a valid glob: {}", name, e))?;
+                Some(NamePattern(glob.compile_matcher()))
+            }
+        };
+        Ok(SwitchConfig {
+            name: raw.name,
+            kind,
+            target_topic: raw.target_topic,
+            pattern,
+            debounce: raw.debounce_ms.map(Duration::from_millis),
+            protocol: raw.protocol,
+            pulselength: raw.pulselength,
+            retain: raw.retain,
+            invert: raw.invert,
+            qos: raw.qos,
+            code_gap: raw.code_gap_ms.map(Duration::from_millis),
+        })
+    }
+}
+
+/// Unknown keys are errors, a typo would otherwise silently leave the intended setting at its
+/// default.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    source: MQTTConnectionConfig,
+    /// Every translated code is published to all of these. A single `[target]` table is
+    /// accepted too.
+    #[serde(alias = "target", deserialize_with = "one_or_many")]
+    targets: Vec<MQTTConnectionConfig>,
+    /// `host:port` of a statsd collector. Metrics are discarded when this is unset.
+    statsd_host: Option<String>,
+    /// Only `udp`, the default, works. Kept so a `tcp` setting fails loudly instead of silently
+    /// sending UDP.
+    #[serde(default)]
+    statsd_protocol: StatsdProtocol,
+    /// Namespace for all metrics, defaults to `DEFAULT_STATSD_PREFIX`.
+    statsd_prefix: Option<String>,
+    /// Only send this fraction of the per-message counters like `publish`, e.g. `0.1` on a busy
+    /// bridge. Errors and reconnects are always sent.
+    statsd_sample_rate: Option<f64>,
+    /// Sentry DSN. Errors are only reported when this is set.
+    sentry_host: Option<String>,
+    /// Every prefix is subscribed to with a trailing `#`. A single `source_topic_prefix` string
+    /// is accepted too.
+    #[serde(
+        alias = "source_topic_prefix",
+        deserialize_with = "deserialize_prefixes"
+    )]
+    source_topic_prefixes: Vec<String>,
+    /// MQTT filters like `gBridge/u1/+/onoff`. When set, other topics under the prefixes are
+    /// dropped before translation.
+    source_topic_filters: Option<Vec<String>>,
+    /// MQTT filters whose messages are republished to the targets unchanged instead of being
+    /// translated, e.g. `sensors/#`. Subscribed to on top of the prefixes.
+    passthrough_filters: Option<Vec<String>>,
+    /// Target topic for passed through messages, with the placeholders of
+    /// `target_topic_template`. Defaults to the source topic.
+    passthrough_topic_template: Option<String>,
+    pub target_topic: String,
+    /// Publish codes retained, so the last command survives a target broker restart.
+    #[serde(default)]
+    target_retain: bool,
+    /// Derive the target topic from the source topic, e.g. `gbridge/{switch}/cmd`. `{switch}`
+    /// is the switch name segment, `{segment:N}` any other. A switch's own `target_topic` wins.
+    target_topic_template: Option<String>,
+    /// Appended to every translated target topic as is, e.g. `/set`.
+    target_topic_suffix: Option<String>,
+    /// Append the source topic segments after the switch name to the target topic, before
+    /// `target_topic_suffix`, so `gBridge/u1/d2777/onoff` publishes to `zap/onoff`.
+    #[serde(default)]
+    target_topic_append_segments: bool,
+    /// Wrap codes before publishing, e.g. `{"code":"{code}","protocol":1}`. `{code}`,
+    /// `{switch}` and `{state}` are replaced with the code, the switch name and `on`/`off`.
+    target_template: Option<String>,
+    /// Case codes are published in, for RF bridges that only take one.
+    #[serde(default)]
+    code_case: CodeCase,
+    /// Index of the `/`-separated topic segment holding the switch name.
+    #[serde(default = "default_switch_name_segment")]
+    pub switch_name_segment: usize,
+    /// Warn once per topic that ends before `switch_name_segment`, usually a wrong prefix.
+    /// With false they're only logged at debug level.
+    #[serde(default = "default_warn_on_short_topic")]
+    warn_on_short_topic: bool,
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos")]
+    source_qos: QoS,
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos")]
+    target_qos: QoS,
+    /// Announce every on/off switch to Home Assistant via retained MQTT discovery messages.
+    #[serde(default)]
+    homeassistant_discovery: bool,
+    /// Address like `0.0.0.0:8080` to serve `/healthz` on. Disabled when unset.
+    health_listen: Option<String>,
+    /// Seconds without a source event after which `/healthz` reports unhealthy.
+    #[serde(default = "default_health_stale_secs")]
+    health_stale_secs: u64,
+    /// How often a failed target publish is retried before the message is dropped.
+    #[serde(default = "default_publish_max_retries")]
+    publish_max_retries: u32,
+    /// Hold up to this many publishes per target while it is disconnected and send them on
+    /// reconnect, dropping the oldest when full. Leave out to not queue anything.
+    offline_queue_size: Option<usize>,
+    /// After forwarding a command, publish the resulting state retained to the source broker on
+    /// `<topic up to the switch name>/state`, e.g. `gBridge/u1/d2777/state`.
+    #[serde(default)]
+    report_state: bool,
+    /// Read the state from this field of JSON payloads, e.g. `state` for `{"state": "ON"}`.
+    /// Payloads without it are used as they are.
+    payload_json_path: Option<String>,
+    /// Skip a command if it resolves to the code last sent for the same switch, e.g. a sensor
+    /// repeating its current state.
+    #[serde(default)]
+    suppress_duplicate_states: bool,
+    /// Only bridge these switches, e.g. for a staged rollout. Others are treated as unmatched.
+    enabled_switches: Option<Vec<String>>,
+    /// Never bridge these switches, even if they are in `enabled_switches`.
+    disabled_switches: Option<Vec<String>>,
+    /// JSON file to keep the last state sent per switch in across restarts.
+    state_file: Option<String>,
+    /// Append a JSON line for every message that wasn't forwarded because it didn't match or
+    /// couldn't be read, to reconcile later what the bridge dropped.
+    audit_log_path: Option<String>,
+    /// Switch whose on and then off code is sent once at startup, to check the path to the
+    /// transmitter works before the first real command.
+    startup_test_switch: Option<String>,
+    /// Log a heartbeat with the connection status and count `heartbeat` this often, so a quiet
+    /// bridge still shows it's alive.
+    heartbeat_interval_secs: Option<u64>,
+    /// Keep at least this long between any two codes sent, across all switches, for
+    /// transmitters that can't keep up with back to back commands.
+    min_send_gap_ms: Option<u64>,
+    /// Exit with an error once this many reconnects in a row to the source or a target failed,
+    /// so an orchestrator can restart the bridge or alert. Retries forever when unset.
+    max_reconnect_attempts: Option<u32>,
+    /// Topic on the source broker taking `reload`, `pause`, `resume` and `stats` commands for the
+    /// bridge itself. Replies go to `<control_topic>/reply`. Retained commands are ignored, they
+    /// would be applied again on every reconnect.
+    control_topic: Option<String>,
+    /// Status topic on the source broker. The broker publishes `lwt_payload` there if the bridge
+    /// drops off without disconnecting, the bridge publishes `online_payload` on every connect.
+    lwt_topic: Option<String>,
+    #[serde(default = "default_lwt_payload")]
+    lwt_payload: String,
+    #[serde(default = "default_online_payload")]
+    online_payload: String,
+    /// Only log what would be published, leaving `state_file` alone. Also enabled by the
+    /// `--dry-run` flag.
+    #[serde(default)]
+    dry_run: bool,
+    /// Exit after forwarding the first message. Also enabled by the `--once` flag.
+    #[serde(default)]
+    once: bool,
+    #[serde(default)]
+    log_format: LogFormat,
+    /// `env_logger` style filter like `debug` or `gbridge_bridge=trace`. `RUST_LOG` wins if set.
+    log_level: Option<String>,
+    pub switches: Vec<SwitchConfig>,
+    /// Several switches set at once by an on command for the scene's name, e.g. all lights off.
+    #[serde(default)]
+    scenes: Vec<SceneConfig>,
+}
+
+/// A name used like a switch name, which sends the codes of its `actions` in order.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct SceneConfig {
+    name: String,
+    actions: Vec<SceneAction>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct SceneAction {
+    switch: String,
+    state: SceneState,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SceneState {
+    On,
+    Off,
+    Toggle,
+}
+
+impl SceneState {
+    /// The payload a switch command for this state has.
+    fn payload(self) -> &'static str {
+        match self {
+            SceneState::On => "on",
+            SceneState::Off => "off",
+            SceneState::Toggle => "toggle",
+        }
+    }
+}
+
+/// What a message naming a scene turns into.
+#[derive(Debug, PartialEq, Eq)]
+enum SceneExpansion {
+    /// Topic and payload of a switch command per action, in order. The topics are the incoming
+    /// one with the member's name in the switch name segment, so templates work as for the
+    /// switch itself.
+    Members(Vec<(String, &'static str)>),
+    /// Scenes can only be activated, any other payload does nothing.
+    NotActivated { scene: String },
+}
+
+/// Expand a message for one of `scenes`, `None` if `topic` doesn't name one.
+fn expand_scene(
+    scenes: &[SceneConfig],
+    topic: &str,
+    payload: &[u8],
+    switch_name_segment: usize,
+) -> Option<SceneExpansion> {
+    let segments: Vec<_> = topic.split('/').collect();
+    let name = segments.get(switch_name_segment)?;
+    let scene = scenes.iter().find(|s| &s.name == name)?;
+    let activated = std::str::from_utf8(payload)
+        .ok()
+        .and_then(parse_switch_state)
+        .unwrap_or(false);
+    if !activated {
+        return Some(SceneExpansion::NotActivated {
+            scene: scene.name.clone(),
+        });
+    }
+    let members = scene
+        .actions
+        .iter()
+        .map(|action| {
+            let mut member = segments.clone();
+            member[switch_name_segment] = &action.switch;
+            (member.join("/"), action.state.payload())
+        })
+        .collect();
+    Some(SceneExpansion::Members(members))
+}
+
+#[derive(Debug, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum CodeCase {
+    Upper,
+    Lower,
+    /// As written in the config.
+    #[default]
+    AsIs,
+}
+
+impl CodeCase {
+    fn apply<'a>(&self, code: &'a str) -> std::borrow::Cow<'a, str> {
+        match self {
+            CodeCase::Upper => code.to_uppercase().into(),
+            CodeCase::Lower => code.to_lowercase().into(),
+            CodeCase::AsIs => code.into(),
+        }
+    }
+}
+
+fn default_switch_name_segment() -> usize {
+    2
+}
+
+fn default_publish_max_retries() -> u32 {
+    3
+}
+
+fn default_health_stale_secs() -> u64 {
+    60
+}
+
+fn default_lwt_payload() -> String {
+    "offline".to_string()
+}
+
+fn default_online_payload() -> String {
+    "online".to_string()
+}
+
+/// Accept either a single value or a list of them.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn optional_one_or_many<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    one_or_many(deserializer).map(Some)
+}
+
+/// Prefixes end in exactly one `/` however they're written, otherwise the `#` subscription and
+/// the switch name segment would silently be off by one.
+fn deserialize_prefixes<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(one_or_many::<D, String>(deserializer)?
+        .into_iter()
+        .map(|prefix| normalize_prefix(&prefix))
+        .collect())
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim().trim_end_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+fn default_qos() -> QoS {
+    QoS::AtLeastOnce
+}
+
+/// QoS levels are configured by their MQTT number.
+fn deserialize_qos<'de, D>(deserializer: D) -> Result<QoS, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match u8::deserialize(deserializer)? {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        n => Err(serde::de::Error::custom(format!(
+            "invalid QoS {}, expected 0, 1 or 2",
+            n
+        ))),
+    }
+}
+
+fn deserialize_optional_qos<'de, D>(deserializer: D) -> Result<Option<QoS>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Level(#[serde(deserialize_with = "deserialize_qos")] QoS);
+
+    Ok(Option::<Level>::deserialize(deserializer)?.map(|Level(qos)| qos))
+}
+
+impl Config {
+    /// The switch name has to come after the fixed subscription prefixes, otherwise every message
+    /// would resolve to the same (prefix) segment. The index is counted from the start of the
+    /// topic, so it applies to every prefix alike.
+    fn check_switch_name_segment(&self) -> Result<(), Error> {
+        for prefix in &self.source_topic_prefixes {
+            let prefix_segments = prefix.split('/').filter(|s| !s.is_empty()).count();
+            if self.switch_name_segment < prefix_segments {
+                return Err(anyhow::anyhow!(
+                    "switch_name_segment {} points into source topic prefix {:?}, expected at \
+                     least {}.",
+                    self.switch_name_segment,
+                    prefix,
+                    prefix_segments
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Each target with the name used for its logs, metrics and environment overrides.
+    fn named_targets(&self) -> impl Iterator<Item = (String, &MQTTConnectionConfig)> {
+        self.targets
+            .iter()
+            .enumerate()
+            .map(|(index, conn)| (target_name(index), conn))
+    }
+
+    /// Whether a source topic should be translated at all.
+    fn accepts_source_topic(&self, topic: &str) -> bool {
+        match &self.source_topic_filters {
+            Some(filters) => filters.iter().any(|filter| topic_matches(filter, topic)),
+            None => true,
+        }
+    }
+
+    /// Where to pass a message on `topic` through to, `None` if it should be translated.
+    fn passthrough_topic(&self, topic: &str) -> Option<String> {
+        let filters = self.passthrough_filters.as_ref()?;
+        if !filters.iter().any(|filter| topic_matches(filter, topic)) {
+            return None;
+        }
+        let template = match &self.passthrough_topic_template {
+            Some(template) => template,
+            None => return Some(topic.to_string()),
+        };
+        Some(
+            render_topic_template(template, topic, self.switch_name_segment).unwrap_or_else(|| {
+                log::warn!(
+                    "passthrough_topic_template {} doesn't fit {}, keeping the topic.",
+                    template,
+                    topic
+                );
+                topic.to_string()
+            }),
+        )
+    }
+
+    pub fn topic_rules(&self) -> TopicRules<'_> {
+        TopicRules {
+            template: self.target_topic_template.as_deref(),
+            suffix: self.target_topic_suffix.as_deref(),
+            append_segments: self.target_topic_append_segments,
+        }
+    }
+
+    /// Everything under each prefix, and the passthrough filters those don't cover already.
+    fn source_topics(&self) -> Vec<String> {
+        let prefixes: Vec<_> = self
+            .source_topic_prefixes
+            .iter()
+            .map(|prefix| format!("{}#", prefix))
+            .collect();
+        let passthrough = self
+            .passthrough_filters
+            .iter()
+            .flatten()
+            .filter(|filter| !prefixes.iter().any(|prefix| topic_matches(prefix, filter)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let control = self
+            .control_topic
+            .iter()
+            .filter(|topic| !prefixes.iter().any(|prefix| topic_matches(prefix, topic)))
+            .cloned()
+            .collect::<Vec<_>>();
+        prefixes
+            .into_iter()
+            .chain(passthrough)
+            .chain(control)
+            .collect()
+    }
+
+    /// Where `stats` and other control replies are published.
+    fn control_reply_topic(&self) -> Option<String> {
+        self.control_topic
+            .as_ref()
+            .map(|topic| format!("{}/reply", topic))
+    }
+
+    /// Check the whole config up front, collecting every problem instead of stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if self.targets.is_empty() {
+            errors.push("No target configured.".to_string());
+        }
+        let connections = std::iter::once(("source".to_string(), &self.source));
+        for (name, conn) in connections.chain(self.named_targets()) {
+            if conn.host.trim().is_empty() {
+                errors.push(format!("{} host is empty.", name));
+            }
+            if conn.mqtt_cap == Some(0) {
+                errors.push(format!("{} mqtt_cap must be at least 1.", name));
+            }
+            match conn.mqtt_version {
+                None | Some(3) => {}
+                Some(5) => errors.push(format!(
+                    "{} mqtt_version 5 is unsupported by rumqttc 0.1, only 3 (MQTT 3.1.1) works.",
+                    name
+                )),
+                Some(other) => errors.push(format!(
+                    "{} mqtt_version must be 3 or 5, got {}.",
+                    name, other
+                )),
+            }
+            if conn.user_properties.is_some() {
+                errors.push(format!(
+                    "{} user_properties need MQTT 5, which is unsupported by rumqttc 0.1.",
+                    name
+                ));
+            }
+            match (conn.transport, &conn.ws_path) {
+                (MqttTransport::Tcp, None) => {}
+                (MqttTransport::Tcp, Some(_)) => errors.push(format!(
+                    "{} ws_path needs transport \"ws\" or \"wss\".",
+                    name
+                )),
+                (transport, _) => errors.push(format!(
+                    "{} transport {:?} is unsupported by rumqttc 0.1, only \"tcp\" works.",
+                    name,
+                    transport.as_str()
+                )),
+            }
+        }
+        if self.source_topic_prefixes.is_empty() {
+            errors.push("No source topic prefix configured.".to_string());
+        }
+        let filters = self.source_topic_filters.iter().flatten();
+        for filter in filters.chain(self.passthrough_filters.iter().flatten()) {
+            if !is_valid_topic_filter(filter) {
+                errors.push(format!("{:?} is not a valid MQTT topic filter.", filter));
+            }
+        }
+        if let Some(host) = self.statsd_host.as_deref().filter(|h| !h.trim().is_empty()) {
+            if let Err(e) = check_statsd_host(host) {
+                errors.push(e.to_string());
+            }
+        }
+        if matches!(&self.statsd_prefix, Some(p) if p.trim().is_empty()) {
+            errors.push("statsd_prefix is empty.".to_string());
+        }
+        if self.target_topic.trim().is_empty() {
+            errors.push("target_topic is empty.".to_string());
+        }
+        if matches!(&self.target_topic_suffix, Some(s) if s.contains(['+', '#'])) {
+            errors.push("target_topic_suffix can't contain MQTT wildcards.".to_string());
+        }
+        if let Some(rate) = self.statsd_sample_rate {
+            if !(rate > 0.0 && rate <= 1.0) {
+                errors.push(format!(
+                    "statsd_sample_rate must be above 0 and at most 1, got {}.",
+                    rate
+                ));
+            }
+        }
+        if self.offline_queue_size == Some(0) {
+            errors.push("offline_queue_size must be at least 1.".to_string());
+        }
+        if self.heartbeat_interval_secs == Some(0) {
+            errors.push("heartbeat_interval_secs must be at least 1.".to_string());
+        }
+        if self.min_send_gap_ms == Some(0) {
+            errors.push("min_send_gap_ms must be at least 1.".to_string());
+        }
+        if matches!(&self.lwt_topic, Some(t) if t.trim().is_empty()) {
+            errors.push("lwt_topic is empty.".to_string());
+        }
+        if let Some(topic) = &self.control_topic {
+            if topic.trim().is_empty() || topic.contains(['+', '#']) {
+                errors.push(format!(
+                    "control_topic {:?} must be a topic without wildcards.",
+                    topic
+                ));
+            }
+        }
+        if let Err(e) = self.check_switch_name_segment() {
+            errors.push(e.to_string());
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        for switch in &self.switches {
+            if switch.name.trim().is_empty() {
+                errors.push("Found a switch with an empty name.".to_string());
+            } else if !seen.insert(&switch.name) {
+                errors.push(format!(
+                    "Switch name {} is used more than once.",
+                    switch.name
+                ));
+            }
+            let has_empty_code = match &switch.kind {
+                SwitchKind::OnOff { on, off } => on
+                    .iter()
+                    .chain(off.iter().flatten())
+                    .any(|code| code.trim().is_empty()),
+                SwitchKind::Dimmer { levels } => levels.iter().any(|l| l.code.trim().is_empty()),
+                SwitchKind::Threshold { on, off, .. } => {
+                    on.iter().chain(off).any(|code| code.trim().is_empty())
+                }
+                SwitchKind::Regex { rules } => rules.iter().any(|r| r.code.trim().is_empty()),
+            };
+            if has_empty_code {
+                errors.push(format!("Switch {} has an empty code.", switch.name));
+            }
+            let enabled = switch_enabled(
+                &switch.name,
+                self.enabled_switches.as_deref(),
+                self.disabled_switches.as_deref(),
+            );
+            let template = self.target_template.as_deref().filter(|_| enabled);
+            for (setting, set) in &[
+                ("protocol", switch.protocol.is_some()),
+                ("pulselength", switch.pulselength.is_some()),
+            ] {
+                let placeholder = format!("{{{}}}", setting);
+                if !set && template.is_some_and(|t| t.contains(&placeholder)) {
+                    errors.push(format!(
+                        "target_template uses {}, but switch {} has no {}.",
+                        placeholder, switch.name, setting
+                    ));
+                }
+            }
+        }
+        for scene in &self.scenes {
+            if scene.name.trim().is_empty() || scene.name.contains('/') {
+                errors.push(format!(
+                    "Scene name {:?} must be one topic segment.",
+                    scene.name
+                ));
+            }
+            if self.switches.iter().any(|s| s.name == scene.name)
+                || self.scenes.iter().filter(|s| s.name == scene.name).count() > 1
+            {
+                errors.push(format!("Scene name {} is used more than once.", scene.name));
+            }
+            if scene.actions.is_empty() {
+                errors.push(format!("Scene {} has no actions.", scene.name));
+            }
+            for action in &scene.actions {
+                let switch = self.switches.iter().find(|s| s.name == action.switch);
+                let enabled = switch_enabled(
+                    &action.switch,
+                    self.enabled_switches.as_deref(),
+                    self.disabled_switches.as_deref(),
+                );
+                let error = match switch {
+                    Some(_) if !enabled => Some(format!(
+                        "Scene {} sets switch {}, which isn't enabled.",
+                        scene.name, action.switch
+                    )),
+                    switch => scene_action_error(&scene.name, action, switch),
+                };
+                errors.extend(error);
+            }
+        }
+        for (key, names) in &[
+            ("enabled_switches", &self.enabled_switches),
+            ("disabled_switches", &self.disabled_switches),
+        ] {
+            for name in names.iter().flatten() {
+                if !self.switches.iter().any(|s| &s.name == name) {
+                    errors.push(format!("{} names unknown switch {}.", key, name));
+                }
+            }
+        }
+        if let Some(name) = &self.startup_test_switch {
+            match self.switches.iter().find(|s| &s.name == name) {
+                Some(SwitchConfig {
+                    kind: SwitchKind::OnOff { .. },
+                    pattern: None,
+                    ..
+                }) => {}
+                Some(_) => errors.push(format!(
+                    "startup_test_switch {} has to be a plain on/off switch.",
+                    name
+                )),
+                None => errors.push(format!(
+                    "startup_test_switch {} is not a configured switch.",
+                    name
+                )),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn apply_env_overrides<F>(&mut self, lookup: F) -> Result<(), Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        self.source.apply_env_overrides("source", &lookup)?;
+        for (index, target) in self.targets.iter_mut().enumerate() {
+            target.apply_env_overrides(&target_name(index), &lookup)?;
+        }
+        Ok(())
+    }
+}
+
+/// MQTT wildcard matching: `+` is exactly one topic level, a trailing `#` any number of them
+/// including none.
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Wildcards have to fill a whole level, and `#` has to be the last one.
+fn is_valid_topic_filter(filter: &str) -> bool {
+    let levels: Vec<_> = filter.split('/').collect();
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(i, level)| match *level {
+            "#" => i == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['#', '+']),
+        })
+}
+
+/// `target` for the first target so single target setups keep their names, then `target2`,
+/// `target3`, ...
+fn target_name(index: usize) -> String {
+    match index {
+        0 => "target".to_string(),
+        n => format!("target{}", n + 1),
+    }
+}
+
+const DEFAULT_STATSD_PREFIX: &str = "gbridge_bridge";
+const DEFAULT_CA_PATH: &str = "/etc/ssl/cert.pem";
+
+const DEFAULT_KEEP_ALIVE_SECS: u16 = 5;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MQTT_CAP: usize = 64;
+const TLS_PORT: u16 = 8883;
+const PLAINTEXT_PORT: u16 = 1883;
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Exponential reconnect delay, doubling from `RECONNECT_MIN_DELAY` up to `RECONNECT_MAX_DELAY`.
+#[derive(Debug)]
+struct Backoff {
+    current: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff {
+            current: RECONNECT_MIN_DELAY,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt, without jitter.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current * 2, RECONNECT_MAX_DELAY);
+        delay
+    }
+
+    /// Like `next_delay`, plus up to a quarter of random jitter so clients don't reconnect in
+    /// lockstep.
+    fn next_delay_with_jitter(&mut self) -> Duration {
+        let delay = self.next_delay();
+        let jitter_ms = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 4);
+        delay + Duration::from_millis(jitter_ms)
+    }
+
+    fn reset(&mut self) {
+        self.current = RECONNECT_MIN_DELAY;
+    }
+}
+
+/// Failed connection attempts in a row, to give up after `max_reconnect_attempts`.
+#[derive(Debug)]
+struct ReconnectLimit {
+    max_attempts: Option<u32>,
+    failures: u32,
+}
+
+impl ReconnectLimit {
+    fn new(max_attempts: Option<u32>) -> Self {
+        ReconnectLimit {
+            max_attempts,
+            failures: 0,
+        }
+    }
+
+    /// Count a failed or dropped connection, erroring once `max_attempts` reconnects after it
+    /// failed as well.
+    fn failed(&mut self, name: &str) -> Result<(), Error> {
+        self.failures += 1;
+        match self.max_attempts {
+            Some(max) if self.failures > max => Err(anyhow::anyhow!(
+                "Giving up on {} after {} failed reconnect attempts.",
+                name,
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Read the CA chain for a connection at runtime so builds don't depend on the host's cert store.
+/// rumqttc takes any bytes and only fails at the TLS handshake, so an empty or broken bundle is
+/// caught here instead.
+fn load_ca_chain(conn: &MQTTConnectionConfig) -> Result<Vec<u8>, Error> {
+    let path = conn.ca_path.as_deref().unwrap_or(DEFAULT_CA_PATH);
+    let ca = fs::read(path).with_context(|| format!("Failed to read CA chain from {}", path))?;
+    let (added, ignored) = rustls::RootCertStore::empty()
+        .add_pem_file(&mut std::io::Cursor::new(&ca))
+        .map_err(|_| anyhow::anyhow!("CA chain {} isn't valid PEM.", path))?;
+    if added == 0 {
+        return Err(anyhow::anyhow!(
+            "CA chain {} has no valid certificates ({} unusable), set ca_path to a PEM bundle.",
+            path,
+            ignored
+        ));
+    }
+    log::debug!("Loaded {} CA certificates from {}.", added, path);
+    Ok(ca)
+}
+
+/// PEM encoded `(certificate, key)` pair.
+type ClientAuth = (Vec<u8>, Vec<u8>);
+
+/// Read the client certificate and key for mutual TLS, if configured.
+fn load_client_auth(name: &str, conn: &MQTTConnectionConfig) -> Result<Option<ClientAuth>, Error> {
+    match (&conn.client_cert_path, &conn.client_key_path) {
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) if !conn.tls => Err(anyhow::anyhow!(
+            "{} client certificates require tls to be enabled.",
+            name
+        )),
+        (Some(cert_path), Some(key_path)) => {
+            let cert = fs::read(cert_path)
+                .with_context(|| format!("Failed to read client certificate from {}", cert_path))?;
+            let key = fs::read(key_path)
+                .with_context(|| format!("Failed to read client key from {}", key_path))?;
+            Ok(Some((cert, key)))
+        }
+        _ => Err(anyhow::anyhow!(
+            "{} needs both client_cert_path and client_key_path for client authentication.",
+            name
+        )),
+    }
+}
+
+/// Verifies broker certificates against a fixed name instead of the host connected to.
+struct ServerNameVerifier {
+    server_name: webpki::DNSName,
+    inner: rustls::WebPKIVerifier,
+}
+
+impl rustls::ServerCertVerifier for ServerNameVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        self.inner.verify_server_cert(
+            roots,
+            presented_certs,
+            self.server_name.as_ref(),
+            ocsp_response,
+        )
+    }
+}
+
+/// The TLS setup rumqttc does for `set_ca` and `set_client_auth`, but checking the certificate
+/// against `server_name`. rumqttc only takes the verifier as part of a whole `ClientConfig`.
+fn tls_client_config(
+    name: &str,
+    server_name: &str,
+    ca: &[u8],
+    client_auth: Option<ClientAuth>,
+) -> Result<rustls::ClientConfig, Error> {
+    let server_name = webpki::DNSNameRef::try_from_ascii_str(server_name)
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "{} tls_server_name {} isn't a valid DNS name.",
+                name,
+                server_name
+            )
+        })?
+        .to_owned();
+    let mut config = rustls::ClientConfig::new();
+    let (added, _) = config
+        .root_store
+        .add_pem_file(&mut std::io::Cursor::new(ca))
+        .map_err(|_| anyhow::anyhow!("Failed to parse the {} CA chain.", name))?;
+    if added == 0 {
+        return Err(anyhow::anyhow!(
+            "No valid certificate in the {} CA chain.",
+            name
+        ));
+    }
+    if let Some((cert, key)) = client_auth {
+        let certs = rustls::internal::pemfile::certs(&mut std::io::Cursor::new(cert))
+            .map_err(|_| anyhow::anyhow!("Failed to parse the {} client certificate.", name))?;
+        let key = rustls::internal::pemfile::rsa_private_keys(&mut std::io::Cursor::new(key))
+            .ok()
+            .and_then(|mut keys| keys.pop())
+            .ok_or_else(|| anyhow::anyhow!("No RSA key in the {} client key file.", name))?;
+        config.set_single_client_cert(certs, key)?;
+    }
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(ServerNameVerifier {
+            server_name,
+            inner: rustls::WebPKIVerifier::new(),
+        }));
+    Ok(config)
+}
+
+/// What to publish for an incoming message.
+#[derive(PartialEq)]
+pub struct Translation<'a> {
+    /// The matched switch, for everything the publish needs beyond the codes.
+    pub switch: &'a SwitchConfig,
+    pub topic: String,
+    /// Sent in this order, usually just one.
+    pub codes: Vec<String>,
+    /// The on/off state the codes switch to, `None` for dimmers.
+    pub state: Option<bool>,
+}
+
+/// Only names the switch, its whole config would drown the rest in logs.
+impl std::fmt::Debug for Translation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Translation")
+            .field("switch", &self.switch.name)
+            .field("topic", &self.topic)
+            .field("codes", &self.codes)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl Translation<'_> {
+    /// The codes as one string, for logs and to compare with the last codes sent.
+    pub fn joined_codes(&self) -> String {
+        self.codes.join(",")
+    }
+}
+
+/// How an incoming message translated, with the reason if it didn't.
+#[derive(Debug, PartialEq)]
+pub enum TranslateResult<'a> {
+    Publish(Translation<'a>),
+    /// No switch has the name in the switch name segment.
+    UnknownSwitch,
+    /// The switch doesn't understand the payload.
+    UnknownPayload,
+    /// The topic ends before the switch name segment.
+    TopicTooShort,
+    /// An off command for a switch that only has an on code.
+    NoOffCode {
+        switch: String,
+    },
+    /// A threshold switch reading that didn't cross the threshold for the other state.
+    NoCrossing {
+        switch: String,
+    },
+}
+
+impl<'a> TranslateResult<'a> {
+    pub fn translation(&self) -> Option<&Translation<'a>> {
+        match self {
+            TranslateResult::Publish(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+/// Resolve an incoming message to the code to publish and where. `last_states` holds the last
+/// state sent per switch, which a `toggle` payload flips. Without one it turns the switch on.
+pub fn map_payload<'a>(
+    topic: &str,
+    payload: &str,
+    switch_name_segment: usize,
+    switch_configs: &'a HashMap<String, SwitchConfig>,
+    default_target_topic: &str,
+    last_states: &HashMap<String, bool>,
+) -> TranslateResult<'a> {
+    let name = match topic.split('/').nth(switch_name_segment) {
+        Some(name) => name,
+        None => return TranslateResult::TopicTooShort,
+    };
+    let c = match find_switch(name, switch_configs) {
+        Some(c) => c,
+        None => return TranslateResult::UnknownSwitch,
+    };
+    let (codes, state) = match &c.kind {
+        SwitchKind::OnOff { on, off } => {
+            let state = match payload.trim() {
+                // Pressing a momentary switch again is its own toggle.
+                "toggle" | "TOGGLE" if off.is_none() => true,
+                "toggle" | "TOGGLE" => !last_states.get(&c.name).copied().unwrap_or(false),
+                payload => match parse_switch_state(payload) {
+                    Some(state) => state,
+                    None => return TranslateResult::UnknownPayload,
+                },
+            };
+            // `state` stays what was asked for, so state reports and toggles follow the device
+            // rather than the code.
+            let codes = match (state != c.invert, off) {
+                (true, _) => on,
+                (false, Some(off)) => off,
+                (false, None) => {
+                    return TranslateResult::NoOffCode {
+                        switch: c.name.clone(),
+                    }
+                }
+            };
+            (codes.clone(), Some(state))
+        }
+        SwitchKind::Dimmer { levels } => {
+            let level = parse_brightness(payload)
+                .and_then(|brightness| levels.iter().rev().find(|l| l.min <= brightness));
+            match level {
+                Some(level) => (vec![level.code.to_string()], None),
+                None => return TranslateResult::UnknownPayload,
+            }
+        }
+        SwitchKind::Threshold {
+            on,
+            off,
+            on_above,
+            off_below,
+        } => {
+            let reading = match payload.trim().parse::<f64>() {
+                Ok(reading) if reading.is_finite() => reading,
+                _ => return TranslateResult::UnknownPayload,
+            };
+            // Before the first crossing either side counts, afterwards only the other one.
+            let state = last_states.get(&c.name).copied();
+            match state {
+                Some(false) | None if reading > *on_above => (on.clone(), Some(true)),
+                Some(true) | None if reading < *off_below => (off.clone(), Some(false)),
+                _ => {
+                    return TranslateResult::NoCrossing {
+                        switch: c.name.clone(),
+                    }
+                }
+            }
+        }
+        SwitchKind::Regex { rules } => match match_payload_rules(rules, payload.trim()) {
+            Some(code) => (vec![code.to_string()], None),
+            None => return TranslateResult::UnknownPayload,
+        },
+    };
+    let target_topic = c
+        .target_topic
+        .as_deref()
+        .unwrap_or(default_target_topic)
+        .to_string();
+    TranslateResult::Publish(Translation {
+        switch: c,
+        topic: target_topic,
+        codes,
+        state,
+    })
+}
+
+/// Exact names win over glob patterns. Of several matching patterns the alphabetically first is
+/// used, so overlaps resolve the same way on every run.
+fn find_switch<'a>(
+    name: &str,
+    switch_configs: &'a HashMap<String, SwitchConfig>,
+) -> Option<&'a SwitchConfig> {
+    switch_configs
+        .get(name)
+        .filter(|c| c.pattern.is_none())
+        .or_else(|| {
+            switch_configs
+                .values()
+                .filter(|c| matches!(&c.pattern, Some(p) if p.0.is_match(name)))
+                .min_by(|a, b| a.name.cmp(&b.name))
+        })
+}
+
+/// How the target topic is derived from the source topic, from the `target_topic_*` options.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TopicRules<'a> {
+    pub template: Option<&'a str>,
+    pub suffix: Option<&'a str>,
+    pub append_segments: bool,
+}
+
+impl TopicRules<'_> {
+    /// The target topic for switches without their own: the template rendered for the source
+    /// `topic`, or `target_topic` if there's none or it doesn't fit.
+    fn default_target_topic(
+        &self,
+        target_topic: &str,
+        topic: &str,
+        switch_name_segment: usize,
+    ) -> String {
+        let template = match self.template {
+            Some(template) => template,
+            None => return target_topic.to_string(),
+        };
+        render_topic_template(template, topic, switch_name_segment).unwrap_or_else(|| {
+            log::warn!(
+                "target_topic_template {} doesn't fit {}, using target_topic.",
+                template,
+                topic
+            );
+            target_topic.to_string()
+        })
+    }
+
+    /// Extend a resolved target topic with the rest of the source topic and the suffix.
+    fn extend(&self, target_topic: &str, topic: &str, switch_name_segment: usize) -> String {
+        let mut extended = target_topic.to_string();
+        if self.append_segments {
+            for segment in topic.split('/').skip(switch_name_segment + 1) {
+                extended.push('/');
+                extended.push_str(segment);
+            }
+        }
+        extended.push_str(self.suffix.unwrap_or(""));
+        extended
+    }
+}
+
+/// Everything the bridge does with a source publish short of sending it on. Payloads have to be
+/// UTF-8, anything else is reported as an error instead of silently not matching.
+/// `target_topic_template` replaces `default_target_topic` for switches without their own.
+pub fn handle_publish<'a>(
+    topic: &str,
+    payload: &[u8],
+    switch_name_segment: usize,
+    switch_configs: &'a HashMap<String, SwitchConfig>,
+    default_target_topic: &str,
+    rules: &TopicRules,
+    last_states: &HashMap<String, bool>,
+) -> Result<TranslateResult<'a>, std::str::Utf8Error> {
+    let payload = std::str::from_utf8(payload)?;
+    let default_target_topic =
+        rules.default_target_topic(default_target_topic, topic, switch_name_segment);
+    let mut translated = map_payload(
+        topic,
+        payload,
+        switch_name_segment,
+        switch_configs,
+        &default_target_topic,
+        last_states,
+    );
+    if let TranslateResult::Publish(t) = &mut translated {
+        t.topic = rules.extend(&t.topic, topic, switch_name_segment);
+    }
+    Ok(translated)
+}
+
+/// Fill `{switch}` and `{segment:N}` in `template` with segments of the source `topic`, so
+/// `gbridge/{switch}/cmd` turns `home/rf/d2777/set` into `gbridge/d2777/cmd`. `None` for unknown
+/// placeholders and segments the topic doesn't have.
+fn render_topic_template(
+    template: &str,
+    topic: &str,
+    switch_name_segment: usize,
+) -> Option<String> {
+    let segments: Vec<_> = topic.split('/').collect();
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        rendered.push_str(&rest[..start]);
+        let index = match &rest[start + 1..end] {
+            "switch" => switch_name_segment,
+            placeholder => placeholder.strip_prefix("segment:")?.parse().ok()?,
+        };
+        rendered.push_str(segments.get(index)?);
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+    Some(rendered)
+}
+
+/// The value at a dot-separated `path` like `state` or `light.state` of a JSON payload, as the
+/// plain string the on/off and brightness parsing expects. Numeric segments index into arrays.
+fn json_field(payload: &[u8], path: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    let value = path.split('.').try_fold(&json, |value, key| match value {
+        serde_json::Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        value => value.get(key),
+    })?;
+    match value {
+        serde_json::Value::String(s) => Some(s.to_string()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// How many distinct too short topics `run` warns about, so a misconfigured prefix on a busy
+/// broker can't grow the set without bound.
+const MAX_SHORT_TOPIC_WARNINGS: usize = 100;
+
+/// Whether `topic` is too short for the first time and still worth a warning, remembering it in
+/// `warned`.
+fn first_short_topic(warned: &mut HashSet<String>, topic: &str) -> bool {
+    warned.len() < MAX_SHORT_TOPIC_WARNINGS && warned.insert(topic.to_string())
+}
+
+/// Whether a command for a switch with a `debounce` window comes too soon after the last one.
+fn is_debounced(debounce: Option<Duration>, last_publish: Option<Instant>, now: Instant) -> bool {
+    match (debounce, last_publish) {
+        (Some(window), Some(last)) => now.saturating_duration_since(last) < window,
+        _ => false,
+    }
+}
+
+/// Where `report_state` confirms a command received on `topic`: the topic cut after the switch
+/// name, plus `/state`.
+fn state_topic(topic: &str, switch_name_segment: usize) -> Option<String> {
+    let segments: Vec<_> = topic.split('/').take(switch_name_segment + 1).collect();
+    if segments.len() <= switch_name_segment {
+        return None;
+    }
+    Some(format!("{}/state", segments.join("/")))
+}
+
+/// Make a switch name safe to use as a statsd metric segment.
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '.' | ':' | '|' | '@' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Map the payload spellings commonly used by MQTT integrations to on (`true`) or off (`false`).
+fn parse_switch_state(payload: &str) -> Option<bool> {
+    match payload.trim() {
+        "1" | "ON" | "on" | "true" => Some(true),
+        "0" | "OFF" | "off" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Brightness percentage for dimmers. Plain on/off payloads map to full and zero brightness.
+fn parse_brightness(payload: &str) -> Option<u8> {
+    match payload.trim().parse::<u8>() {
+        Ok(b) if b <= 100 => Some(b),
+        Ok(_) => None,
+        Err(_) => parse_switch_state(payload).map(|on| if on { 100 } else { 0 }),
+    }
+}
+
+/// A switch's own `retain` wins over the global `target_retain`.
+fn target_retain(switch: &SwitchConfig, config: &Config) -> bool {
+    switch.retain.unwrap_or(config.target_retain)
+}
+
+fn target_qos(switch: &SwitchConfig, config: &Config) -> QoS {
+    switch.qos.unwrap_or(config.target_qos)
+}
+
+/// Structured payload for switches with RF transmitter settings.
+#[derive(Debug, Serialize)]
+struct RfPayload<'a> {
+    code: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pulselength: Option<u32>,
+}
+
+/// What actually gets published for a code. `target_template` wins, with `{code}`, `{switch}`,
+/// `{state}`, `{protocol}` and `{pulselength}` filled in. `{state}` is the `on` or `off` that
+/// was asked for, and `validate` makes sure every switch has the RF settings the template
+/// uses. Otherwise switches with RF settings get an
+/// `RfPayload` and the rest the bare code. The code is in `code_case` either way.
+pub fn target_payload(
+    code: &str,
+    state: Option<bool>,
+    switch: &SwitchConfig,
+    config: &Config,
+) -> String {
+    let code = &*config.code_case.apply(code);
+    let number = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or_default();
+    let state = match state {
+        Some(true) => "on",
+        Some(false) => "off",
+        None => "",
+    };
+    match &config.target_template {
+        Some(template) => fill_placeholders(template, |placeholder| match placeholder {
+            "code" => Some(code.to_string()),
+            "switch" => Some(switch.name.clone()),
+            "state" => Some(state.to_string()),
+            "protocol" => Some(number(switch.protocol)),
+            "pulselength" => Some(number(switch.pulselength)),
+            _ => None,
+        }),
+        None if switch.protocol.is_some() || switch.pulselength.is_some() => {
+            let payload = RfPayload {
+                code,
+                protocol: switch.protocol,
+                pulselength: switch.pulselength,
+            };
+            // Serializing a struct of strings and numbers can't fail.
+            serde_json::to_string(&payload).unwrap_or_else(|_| code.to_string())
+        }
+        None => code.to_string(),
+    }
+}
+
+/// Replace each `{name}` in `template` that `value` knows, in a single pass so a code or switch
+/// name that looks like a placeholder isn't expanded again. Other braces, like those of a JSON
+/// template, are left alone.
+fn fill_placeholders(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = rest
+            .find('}')
+            .and_then(|end| Some((value(&rest[1..end])?, end)));
+        match placeholder {
+            Some((value, end)) => {
+                filled.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Payload of a Home Assistant MQTT switch discovery message.
+#[derive(Debug, Serialize)]
+struct DiscoveryConfig<'a> {
+    name: &'a str,
+    command_topic: &'a str,
+    payload_on: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_off: Option<String>,
+    unique_id: String,
+}
+
+/// Where `handle_publish` sends codes for `switch`, with the source topic naming it under the
+/// first `source_topic_prefix`.
+fn switch_target_topic(switch: &SwitchConfig, config: &Config) -> String {
+    let rules = config.topic_rules();
+    let prefix = config
+        .source_topic_prefixes
+        .first()
+        .map_or("", |p| p.as_str());
+    let topic = format!("{}{}", prefix, switch.name);
+    let target_topic = switch.target_topic.clone().unwrap_or_else(|| {
+        rules.default_target_topic(&config.target_topic, &topic, config.switch_name_segment)
+    });
+    rules.extend(&target_topic, &topic, config.switch_name_segment)
+}
+
+/// The discovery `(topic, payload)` for a switch. Home Assistant only knows on/off switches, so
+/// dimmers aren't announced. Neither are glob switches, which don't stand for a single device.
+fn discovery_message(switch: &SwitchConfig, config: &Config) -> Option<(String, String)> {
+    if switch.pattern.is_some() {
+        return None;
+    }
+    if let SwitchKind::OnOff { on, off } = &switch.kind {
+        let command_topic = switch_target_topic(switch, config);
+        let payload = DiscoveryConfig {
+            name: &switch.name,
+            command_topic: &command_topic,
+            // Home Assistant publishes these itself, so they have to look like what we'd send.
+            // It only sends one payload, so code lists are cut down to their first code.
+            payload_on: target_payload(&on[0], Some(true), switch, config),
+            payload_off: off
+                .as_ref()
+                .map(|off| target_payload(&off[0], Some(false), switch, config)),
+            unique_id: format!("gbridge_bridge_{}", switch.name),
+        };
+        let topic = format!("homeassistant/switch/{}/config", switch.name);
+        // Serializing a struct of strings can't fail.
+        Some((topic, serde_json::to_string(&payload).ok()?))
+    } else {
+        None
+    }
+}
+
+async fn publish_discovery(
+    publisher: &TargetPublisher,
+    switches: &HashMap<String, SwitchConfig>,
+    config: &Config,
+) -> Result<(), Error> {
+    for switch in switches.values() {
+        if let Some((topic, payload)) = discovery_message(switch, config) {
+            if config.dry_run {
+                log::info!("WOULD publish {} to {}", payload, topic);
+                continue;
+            }
+            log::info!("Publishing Home Assistant discovery for {}.", &switch.name);
+            publisher
+                .publish_waiting(&topic, QoS::AtLeastOnce, true, payload.as_bytes())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop switches not in `enabled` (if given) or in `disabled`. Being disabled wins.
+fn filter_switches(
+    switches: Vec<SwitchConfig>,
+    enabled: Option<&[String]>,
+    disabled: Option<&[String]>,
+) -> Vec<SwitchConfig> {
+    switches
+        .into_iter()
+        .filter(|s| switch_enabled(&s.name, enabled, disabled))
+        .collect()
+}
+
+fn switch_enabled(name: &str, enabled: Option<&[String]>, disabled: Option<&[String]>) -> bool {
+    enabled.is_none_or(|names| names.iter().any(|n| n == name))
+        && !disabled.is_some_and(|names| names.iter().any(|n| n == name))
+}
+
+/// What is wrong with a scene `action` if `switch`, the one it names, can't be set by it.
+fn scene_action_error(
+    scene: &str,
+    action: &SceneAction,
+    switch: Option<&SwitchConfig>,
+) -> Option<String> {
+    match switch {
+        Some(SwitchConfig {
+            kind: SwitchKind::OnOff { .. },
+            pattern: None,
+            ..
+        }) => None,
+        Some(_) => Some(format!(
+            "Scene {} can only set plain on/off switches, not {}.",
+            scene, action.switch
+        )),
+        None => Some(format!(
+            "Scene {} names unknown switch {}.",
+            scene, action.switch
+        )),
+    }
+}
+
+/// Using `name` as key, make switch configs faster and more convenient to lookup. Fails on
+/// duplicate names rather than letting the last one silently win.
+pub fn prepare_switch_configs(
+    configs: Vec<SwitchConfig>,
+) -> Result<HashMap<String, SwitchConfig>, Error> {
+    let mut map = HashMap::with_capacity(configs.len());
+    let mut duplicates = Vec::new();
+    for c in configs {
+        if map.contains_key(&c.name) {
+            if !duplicates.contains(&c.name) {
+                duplicates.push(c.name.to_string());
+            }
+        } else {
+            map.insert(c.name.to_string(), c);
+        }
+    }
+    if duplicates.is_empty() {
+        Ok(map)
+    } else {
+        Err(anyhow::anyhow!(
+            "Duplicate switch names: {}",
+            duplicates.join(", ")
+        ))
+    }
+}
+
+/// Filter and index the switches of `config`, taking them out of it so the rest can still be
+/// borrowed.
+fn take_switch_configs(config: &mut Config) -> Result<HashMap<String, SwitchConfig>, Error> {
+    let switches = filter_switches(
+        std::mem::take(&mut config.switches),
+        config.enabled_switches.as_deref(),
+        config.disabled_switches.as_deref(),
+    );
+    for switch in switches.iter().filter(|s| s.name.contains('/')) {
+        log::warn!(
+            "Switch {} contains a /, it can't match a single topic segment.",
+            switch.name
+        );
+    }
+    prepare_switch_configs(switches)
+}
+
+/// The switch table `run` translates with. Reloading swaps the inner map as a whole, so every
+/// message is translated against one version of it.
+type SharedSwitches = Arc<RwLock<Arc<HashMap<String, SwitchConfig>>>>;
+
+/// Re-read the config at `path` and swap its switches into `switches`, returning how many there
+/// are now. Everything else in the file only takes effect on a restart, so the running `scenes`
+/// have to work with the new switches too. On any error the current switches stay in place.
+fn reload_switches(
+    path: &str,
+    scenes: &[SceneConfig],
+    switches: &SharedSwitches,
+) -> Result<usize, Error> {
+    let mut config = Config::from_path(std::path::Path::new(path))?;
+    let reloaded = take_switch_configs(&mut config)?;
+    let errors: Vec<_> = scenes
+        .iter()
+        .flat_map(|scene| {
+            let reloaded = &reloaded;
+            scene.actions.iter().filter_map(move |action| {
+                scene_action_error(&scene.name, action, reloaded.get(&action.switch))
+            })
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(ConfigErrors(errors).into());
+    }
+    let count = reloaded.len();
+    *switches.write().expect("Switch table lock poisoned") = Arc::new(reloaded);
+    Ok(count)
+}
+
+/// Command line: `gbridge-bridge [--dry-run | --validate | --list-switches] <config.toml>`,
+/// `gbridge-bridge --once [--timeout <secs>] <config.toml>`,
+/// `gbridge-bridge --replay <messages.jsonl> <config.toml>` or `gbridge-bridge --version`. The
+/// config path can be `-` for stdin, or left out when `GBRIDGE_CONFIG` is set.
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    config_path: Option<String>,
+    dry_run: bool,
+    /// Check the config, print its switches and exit without connecting.
+    validate: bool,
+    /// Print `build_info` and exit.
+    version: bool,
+    /// Print what every enabled switch sends where and exit without connecting.
+    list_switches: bool,
+    once: bool,
+    /// With `--once`, fail if nothing was forwarded within this long.
+    timeout: Option<Duration>,
+    /// Translate the messages recorded in this file and exit without connecting.
+    replay: Option<String>,
+}
+
+fn parse_args<I>(args: I) -> Result<Args, Error>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => parsed.dry_run = true,
+            "--validate" => parsed.validate = true,
+            "--version" => parsed.version = true,
+            "--list-switches" => parsed.list_switches = true,
+            "--once" => parsed.once = true,
+            "--replay" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--replay needs a file."))?;
+                parsed.replay = Some(path);
+            }
+            "--timeout" => {
+                let secs = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--timeout needs a number of seconds."))?;
+                let secs: u64 = secs
+                    .parse()
+                    .with_context(|| format!("Invalid --timeout {}", secs))?;
+                parsed.timeout = Some(Duration::from_secs(secs));
+            }
+            flag if flag.starts_with("--") => {
+                return Err(anyhow::anyhow!("Unknown option {}.", flag));
+            }
+            _ if parsed.config_path.is_some() => {
+                return Err(anyhow::anyhow!("Unexpected argument {}.", arg));
+            }
+            _ => parsed.config_path = Some(arg),
+        }
+    }
+    if parsed.timeout.is_some() && !parsed.once {
+        return Err(anyhow::anyhow!(
+            "--timeout only works together with --once."
+        ));
+    }
+    Ok(parsed)
+}
+
+/// One line per switch for `--validate`.
+fn switch_summary(switch: &SwitchConfig, default_target_topic: &str) -> String {
+    let kind = match &switch.kind {
+        SwitchKind::OnOff { off: Some(_), .. } => "on/off".to_string(),
+        SwitchKind::OnOff { off: None, .. } => "on only".to_string(),
+        SwitchKind::Dimmer { levels } => format!("dimmer, {} levels", levels.len()),
+        SwitchKind::Threshold {
+            on_above,
+            off_below,
+            ..
+        } => format!("threshold, on above {}, off below {}", on_above, off_below),
+        SwitchKind::Regex { rules } => format!("regex, {} rules", rules.len()),
+    };
+    let topic = switch
+        .target_topic
+        .as_deref()
+        .unwrap_or(default_target_topic);
+    let glob = if switch.pattern.is_some() {
+        ", glob"
+    } else {
+        ""
+    };
+    format!("{} ({}{}) -> {}", switch.name, kind, glob, topic)
+}
+
+/// Crate version and the commit it was built from, see `build.rs`.
+fn build_info() -> String {
+    format!(
+        "gbridge-bridge {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_HASH")
+    )
+}
+
+/// One recorded source message in a `--replay` file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplayMessage {
+    topic: String,
+    payload: String,
+}
+
+/// Run every line of a `--replay` file of `{"topic": ..., "payload": ...}` objects through the
+/// translation like `run` would, one output line per message with what it would publish.
+/// States carry over from line to line, so toggles behave like on a running bridge.
+fn replay<R: BufRead>(
+    reader: R,
+    switches: Vec<SwitchConfig>,
+    config: &Config,
+) -> Result<String, Error> {
+    let switch_configs = prepare_switch_configs(switches)?;
+    let mut states = HashMap::new();
+    let mut output = String::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: ReplayMessage = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid replay line {}", number + 1))?;
+        let result = if let Some(topic) = config.passthrough_topic(&message.topic) {
+            format!("passthrough {:?} to {}", message.payload, topic)
+        } else if !config.accepts_source_topic(&message.topic) {
+            "not in source_topic_filters".to_string()
+        } else {
+            let payload = match &config.payload_json_path {
+                Some(path) => json_field(message.payload.as_bytes(), path)
+                    .unwrap_or_else(|| message.payload.clone()),
+                None => message.payload.clone(),
+            };
+            let expansion = expand_scene(
+                &config.scenes,
+                &message.topic,
+                payload.as_bytes(),
+                config.switch_name_segment,
+            );
+            match expansion {
+                Some(SceneExpansion::Members(members)) => members
+                    .iter()
+                    .map(|(topic, payload)| {
+                        replay_one(topic, payload, &switch_configs, &mut states, config)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join("; "),
+                Some(SceneExpansion::NotActivated { scene }) => {
+                    format!("scene {} only reacts to on", scene)
+                }
+                None => replay_one(
+                    &message.topic,
+                    &payload,
+                    &switch_configs,
+                    &mut states,
+                    config,
+                )?,
+            }
+        };
+        output.push_str(&format!(
+            "{} {:?} -> {}\n",
+            message.topic, message.payload, result
+        ));
+    }
+    Ok(output)
+}
+
+/// What `replay` prints for one switch command.
+fn replay_one(
+    topic: &str,
+    payload: &str,
+    switch_configs: &HashMap<String, SwitchConfig>,
+    states: &mut HashMap<String, bool>,
+    config: &Config,
+) -> Result<String, Error> {
+    let translated = handle_publish(
+        topic,
+        payload.as_bytes(),
+        config.switch_name_segment,
+        switch_configs,
+        &config.target_topic,
+        &config.topic_rules(),
+        states,
+    )?;
+    Ok(match translated {
+        TranslateResult::Publish(t) => {
+            if let Some(state) = t.state {
+                states.insert(t.switch.name.clone(), state);
+            }
+            t.codes
+                .iter()
+                .map(|code| {
+                    let payload = target_payload(code, t.state, t.switch, config);
+                    format!("{} to {}", payload, t.topic)
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+        TranslateResult::UnknownSwitch => "no matching switch".to_string(),
+        TranslateResult::UnknownPayload => "unknown payload".to_string(),
+        TranslateResult::TopicTooShort => "topic too short".to_string(),
+        TranslateResult::NoOffCode { switch } => format!("{} has no off code", switch),
+        TranslateResult::NoCrossing { switch } => format!("{} unchanged", switch),
+    })
+}
+
+/// A table of `switches` with the payloads they send, their target topic and flags, for
+/// `--list-switches`.
+fn switch_table(switches: &[SwitchConfig], config: &Config) -> String {
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "ON".to_string(),
+        "OFF".to_string(),
+        "TOPIC".to_string(),
+        "FLAGS".to_string(),
+    ]];
+    for switch in switches {
+        let payload = |code: &str, state| target_payload(code, state, switch, config);
+        let payloads = |codes: &[String], state| {
+            codes
+                .iter()
+                .map(|code| payload(code, state))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let (on, off) = match &switch.kind {
+            SwitchKind::OnOff { on, off } => {
+                // Inverted switches send the other code, like `map_payload` does.
+                let column = |state: bool| {
+                    let codes = match (state != switch.invert, off) {
+                        (true, _) => Some(&on[..]),
+                        (false, off) => off.as_deref(),
+                    };
+                    codes
+                        .map(|codes| payloads(codes, Some(state)))
+                        .unwrap_or_else(|| "-".to_string())
+                };
+                (column(true), column(false))
+            }
+            SwitchKind::Dimmer { levels } => (
+                levels
+                    .iter()
+                    .map(|l| format!("{}={}", l.min, payload(&l.code, None)))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                "-".to_string(),
+            ),
+            SwitchKind::Threshold {
+                on,
+                off,
+                on_above,
+                off_below,
+            } => (
+                format!(">{} {}", on_above, payloads(on, Some(true))),
+                format!("<{} {}", off_below, payloads(off, Some(false))),
+            ),
+            SwitchKind::Regex { rules } => (
+                rules
+                    .iter()
+                    .map(|r| format!("/{}/={}", r.regex.0.as_str(), payload(&r.code, None)))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                "-".to_string(),
+            ),
+        };
+        let topic = switch
+            .target_topic
+            .as_deref()
+            .or(config.target_topic_template.as_deref())
+            .unwrap_or(&config.target_topic);
+        // The remaining segments depend on the source topic, so they're left as a placeholder.
+        let topic = format!(
+            "{}{}{}",
+            topic,
+            if config.target_topic_append_segments {
+                "/..."
+            } else {
+                ""
+            },
+            config.target_topic_suffix.as_deref().unwrap_or("")
+        );
+        let flags: Vec<_> = [
+            ("glob", switch.pattern.is_some()),
+            ("invert", switch.invert),
+            ("retain", target_retain(switch, config)),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| *flag)
+        .collect();
+        rows.push([switch.name.clone(), on, off, topic, flags.join(",")]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            format!("{}\n", cells.join("  ").trim_end())
+        })
+        .collect()
+}
+
+/// Read the config from `path`, with `-` meaning `stdin`. Without a path `env_config`, i.e.
+/// `GBRIDGE_CONFIG`, is used, holding either a path or the config itself. Returns a name for
+/// messages along with the contents, or `None` if there is no config at all. `env_format`, i.e.
+/// `GBRIDGE_CONFIG_FORMAT`, says what format an inline config is in, otherwise it is sniffed.
+fn read_config<R: std::io::Read>(
+    path: Option<&str>,
+    env_config: Option<String>,
+    env_format: Option<String>,
+    mut stdin: R,
+) -> Result<Option<(String, String)>, Error> {
+    match (path, env_config) {
+        (Some("-"), _) => {
+            let mut contents = String::new();
+            stdin
+                .read_to_string(&mut contents)
+                .context("Failed to read the config from stdin")?;
+            Ok(Some(("stdin".to_string(), contents)))
+        }
+        (Some(path), _) => {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config {}", path))?;
+            Ok(Some((path.to_string(), contents)))
+        }
+        (None, Some(config)) if env_format.is_some() => Ok(Some((
+            format!("GBRIDGE_CONFIG.{}", env_format.unwrap_or_default()),
+            config,
+        ))),
+        // Paths practically never span lines or start with a `{`, configs in any format do.
+        (None, Some(config)) if config.contains('\n') || config.trim_start().starts_with('{') => {
+            Ok(Some(("GBRIDGE_CONFIG".to_string(), config)))
+        }
+        (None, Some(path)) => read_config(Some(&path), None, None, stdin),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Guess the format of a config that has no file name to go by: JSON starts with a `{`, and
+/// the first setting has an `=` before any `:` in TOML, the other way round in YAML.
+fn sniff_config_format(contents: &str) -> &'static str {
+    let first = contents
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .unwrap_or_default();
+    if first.starts_with('{') {
+        return "json";
+    }
+    if first == "---" {
+        return "yaml";
+    }
+    match (first.find('='), first.find(':')) {
+        (None, Some(_)) => "yaml",
+        (Some(eq), Some(colon)) if colon < eq => "yaml",
+        _ => "toml",
+    }
+}
+
+/// Replace every `${NAME}` in the raw config with that environment variable. Done on the text
+/// so it works for all formats and keys alike; values go in as they are, without escaping.
+/// Comment lines are left alone, so examples in them don't need to be set.
+fn interpolate_env<F>(contents: &str, lookup: F) -> Result<String, Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut interpolated = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        if line.trim_start().starts_with('#') {
+            interpolated.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find("${") {
+            interpolated.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated ${{ in {:?}.", line.trim()))?;
+            let name = &after[..end];
+            let value = lookup(name).ok_or_else(|| {
+                anyhow::anyhow!("Config references ${{{}}}, but it isn't set.", name)
+            })?;
+            interpolated.push_str(&value);
+            rest = &after[end + 1..];
+        }
+        interpolated.push_str(rest);
+    }
+    Ok(interpolated)
+}
+
+/// Everything `Config::validate` found wrong with a config, one message per problem.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.join(" "))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl Config {
+    /// Read, interpolate, parse, apply the environment overrides to and validate the config at
+    /// `path`. Validation problems come back as `ConfigErrors`.
+    pub fn from_path(path: &std::path::Path) -> Result<Config, Error> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        Config::load(&path.to_string_lossy(), &contents)
+    }
+
+    /// Like `from_path` for a config read already. `name` picks the format like a path would.
+    pub fn load(name: &str, contents: &str) -> Result<Config, Error> {
+        let contents = interpolate_env(contents, |k| env::var(k).ok())
+            .with_context(|| format!("Failed to interpolate {}", name))?;
+        let mut config = parse_config(name, &contents)?;
+        config.apply_env_overrides(|k| env::var(k).ok())?;
+        config.validate().map_err(ConfigErrors)?;
+        Ok(config)
+    }
+}
+
+/// Parse a config in the format its file extension asks for. Anything without an extension,
+/// like stdin, is TOML.
+fn parse_config(path: &str, contents: &str) -> Result<Config, Error> {
+    let extension = match path {
+        "GBRIDGE_CONFIG" | "stdin" => Some(sniff_config_format(contents).to_string()),
+        _ => std::path::Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase()),
+    };
+    let config: Result<Config, Error> = match extension.as_deref() {
+        None | Some("toml") => toml::from_str(contents).map_err(Error::from),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(contents).map_err(Error::from),
+        Some("json") => serde_json::from_str(contents).map_err(Error::from),
+        Some(other) => {
+            return Err(anyhow::anyhow!(
+                "Unknown config format .{} for {}, use .toml, .yaml, .yml or .json.",
+                other,
+                path
+            ))
+        }
+    };
+    // Everything else has a default, so say what can't be left out.
+    config.map_err(|e| {
+        if e.to_string().contains("missing field") {
+            e.context(format!(
+                "{} is missing a required setting. Every config needs source_topic_prefix, \
+                 target_topic, host and user for [source] and [target], and [[switches]].",
+                path
+            ))
+        } else {
+            e
+        }
+    })
+}
+
+/// The `gbridge-bridge` command line, all the binary does is call this.
+pub fn cli() -> Result<(), Error> {
+    let args = parse_args(env::args().skip(1))?;
+    if args.version {
+        println!("{}", build_info());
+        return Ok(());
+    }
+    let source = read_config(
+        args.config_path.as_deref(),
+        env::var("GBRIDGE_CONFIG").ok(),
+        env::var("GBRIDGE_CONFIG_FORMAT").ok(),
+        std::io::stdin(),
+    )?;
+    if let Some((path, contents)) = source {
+        let mut config = match Config::load(&path, &contents) {
+            Ok(config) => config,
+            Err(e) => match e.downcast_ref::<ConfigErrors>() {
+                Some(ConfigErrors(errors)) => {
+                    for e in errors {
+                        eprintln!("ERR: {}", e);
+                    }
+                    std::process::exit(1);
+                }
+                None => return Err(e),
+            },
+        };
+        config.dry_run |= args.dry_run;
+        config.once |= args.once;
+        if args.validate {
+            println!("{} is valid, {} switches:", path, config.switches.len());
+            for switch in &config.switches {
+                println!("  {}", switch_summary(switch, &config.target_topic));
+            }
+            return Ok(());
+        }
+        if args.list_switches {
+            let switches = filter_switches(
+                std::mem::take(&mut config.switches),
+                config.enabled_switches.as_deref(),
+                config.disabled_switches.as_deref(),
+            );
+            print!("{}", switch_table(&switches, &config));
+            return Ok(());
+        }
+        if let Some(replay_path) = &args.replay {
+            let file = fs::File::open(replay_path)
+                .with_context(|| format!("Failed to open {}", replay_path))?;
+            let switches = filter_switches(
+                std::mem::take(&mut config.switches),
+                config.enabled_switches.as_deref(),
+                config.disabled_switches.as_deref(),
+            );
+            print!("{}", replay(BufReader::new(file), switches, &config)?);
+            return Ok(());
+        }
+        let guard = init_logs(&config);
+        log::info!("{}", build_info());
+        let metrics = init_metrics(&config)?;
+        let mut runtime = tokio::runtime::Runtime::new()?;
+        let timeout = args.timeout;
+        // Only a config file can be read again, not stdin or GBRIDGE_CONFIG holding the config.
+        let reload_path = Some(path).filter(|p| std::path::Path::new(p).is_file());
+        let signal = async move {
+            match timeout {
+                Some(timeout) => tokio::select! {
+                    result = shutdown_signal() => result,
+                    _ = tokio::time::delay_for(timeout) => Err(anyhow::anyhow!(
+                        "Nothing forwarded within {}s.",
+                        timeout.as_secs()
+                    )),
+                },
+                None => shutdown_signal().await,
+            }
+        };
+        runtime
+            .block_on(run(config, metrics, signal, reload_path))
+            .inspect_err(|e| {
+                if guard.is_some() {
+                    sentry_anyhow::capture_anyhow(e);
+                }
+            })
+    } else {
+        eprintln!("ERR: Missing configuration argument and GBRIDGE_CONFIG is unset.");
+        Ok(())
+    }
+}
+
+fn json_log_line(timestamp: &str, record: &log::Record) -> serde_json::Value {
+    serde_json::json!({
+        "timestamp": timestamp,
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+}
+
+/// Pick the log filter from `RUST_LOG`, then the config, then fall back to `info`.
+fn log_filters(rust_log: Option<String>, log_level: Option<&str>) -> String {
+    rust_log
+        .filter(|f| !f.trim().is_empty())
+        .or_else(|| log_level.map(|l| l.to_string()))
+        .unwrap_or_else(|| "info".to_string())
+}
+
+/// Set up logging, plus Sentry if a DSN is configured.
+fn init_logs(config: &Config) -> Option<sentry::ClientInitGuard> {
+    let mut log_builder = pretty_env_logger::formatted_builder();
+    log_builder.parse_filters(&log_filters(
+        env::var("RUST_LOG").ok(),
+        config.log_level.as_deref(),
+    ));
+    if config.log_format == LogFormat::Json {
+        log_builder.format(|buf, record| {
+            let line = json_log_line(&buf.timestamp().to_string(), record);
+            writeln!(buf, "{}", line)
+        });
+    }
+    let env_logger = log_builder.build();
+    let max_level = env_logger.filter();
+    let logger = sentry_log::SentryLogger::with_dest(env_logger);
+
+    log::set_boxed_logger(Box::new(logger)).expect("Setting logger failed");
+    log::set_max_level(max_level);
+
+    let sentry_host = config
+        .sentry_host
+        .as_deref()
+        .filter(|h| !h.trim().is_empty())?;
+    // TODO: Inline once we have stable type ascription.
+    let client_options: sentry::ClientOptions = sentry_host.into();
+    Some(sentry::init(client_options))
+}
+
+/// Where metrics go. Without a statsd host every call is a no-op, so call sites don't need to
+/// care whether metrics are enabled.
+enum Metrics {
+    Statsd {
+        client: statsd::Client,
+        /// Applied by `incr_sampled`, see `statsd_sample_rate`.
+        sample_rate: f64,
+    },
+    Noop,
+}
+
+impl Metrics {
+    fn incr(&self, metric: &str) {
+        if let Metrics::Statsd { client, .. } = self {
+            client.incr(metric);
+        }
+    }
+
+    /// `incr` for counters that go up with every message.
+    fn incr_sampled(&self, metric: &str) {
+        match self {
+            Metrics::Statsd {
+                client,
+                sample_rate,
+            } if *sample_rate < 1.0 => client.sampled_count(metric, 1.0, *sample_rate),
+            metrics => metrics.incr(metric),
+        }
+    }
+
+    /// `incr_sampled` by `value` instead of one, e.g. for byte counts.
+    fn count_sampled(&self, metric: &str, value: usize) {
+        match self {
+            Metrics::Statsd {
+                client,
+                sample_rate,
+            } if *sample_rate < 1.0 => client.sampled_count(metric, value as f64, *sample_rate),
+            Metrics::Statsd { client, .. } => client.count(metric, value as f64),
+            Metrics::Noop => {}
+        }
+    }
+
+    fn gauge(&self, metric: &str, value: f64) {
+        if let Metrics::Statsd { client, .. } = self {
+            client.gauge(metric, value);
+        }
+    }
+
+    fn timer(&self, metric: &str, elapsed: Duration) {
+        if let Metrics::Statsd { client, .. } = self {
+            client.timer(metric, elapsed.as_secs_f64() * 1000.0);
+        }
+    }
+
+    fn time<F, R>(&self, metric: &str, callable: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        match self {
+            Metrics::Statsd { client, .. } => client.time(metric, callable),
+            Metrics::Noop => callable(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum StatsdProtocol {
+    #[default]
+    Udp,
+    /// Accepted so the config can say what it wants, but the statsd client only speaks UDP.
+    Tcp,
+}
+
+/// Catch URLs and bare hostnames before they turn into an opaque resolver error.
+fn check_statsd_host(host: &str) -> Result<(), Error> {
+    let valid = match host.rsplit_once(':') {
+        Some((name, port)) => {
+            !name.is_empty() && !name.contains('/') && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "statsd_host must be host:port, got {:?}.",
+            host
+        ))
+    }
+}
+
+fn init_metrics(config: &Config) -> Result<Metrics, Error> {
+    let host = match config.statsd_host.as_deref() {
+        Some(host) if !host.trim().is_empty() => host,
+        _ => return Ok(Metrics::Noop),
+    };
+    check_statsd_host(host)?;
+    if config.statsd_protocol == StatsdProtocol::Tcp {
+        return Err(anyhow::anyhow!(
+            "statsd_protocol tcp is not supported, metrics can only be sent over UDP."
+        ));
+    }
+    let prefix = config
+        .statsd_prefix
+        .as_deref()
+        .unwrap_or(DEFAULT_STATSD_PREFIX);
+    let client = statsd::Client::new(host, prefix)
+        .with_context(|| format!("Couldn't resolve statsd_host {}", host))?;
+    Ok(Metrics::Statsd {
+        client,
+        sample_rate: config.statsd_sample_rate.unwrap_or(1.0),
+    })
+}
+
+/// Shared `MqttOptions` setup for both ends of the bridge. `name` doubles as the default client id.
+fn build_mqtt_options(name: &str, conn: &MQTTConnectionConfig) -> Result<MqttOptions, Error> {
+    let client_id = conn.client_id.as_deref().unwrap_or(name);
+    // rumqttc panics on these.
+    if client_id.is_empty() || client_id.starts_with(' ') {
+        return Err(anyhow::anyhow!(
+            "{} client_id {:?} must not be empty or start with a space.",
+            name,
+            client_id
+        ));
+    }
+    if let Some(proxy) = &conn.proxy {
+        return Err(anyhow::anyhow!(
+            "{} proxy {} can't be used, connecting through a proxy isn't supported.",
+            name,
+            proxy
+        ));
+    }
+    let port = conn.port();
+    let keep_alive = conn.keep_alive_secs.unwrap_or(DEFAULT_KEEP_ALIVE_SECS);
+    // rumqttc panics on anything shorter.
+    if keep_alive < 5 {
+        return Err(anyhow::anyhow!(
+            "{} keep_alive_secs must be at least 5, got {}.",
+            name,
+            keep_alive
+        ));
+    }
+    let connect_timeout = conn
+        .connect_timeout_secs
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS);
+    if connect_timeout == 0 {
+        return Err(anyhow::anyhow!(
+            "{} connect_timeout_secs must be at least 1.",
+            name
+        ));
+    }
+    let clean_session = conn.clean_session.unwrap_or(true);
+    if !clean_session && conn.client_id.is_none() {
+        log::warn!(
+            "{} keeps its session under the default client id {}, set a unique client_id.",
+            name,
+            client_id
+        );
+    }
+    let mut options = MqttOptions::new(client_id, &conn.host, port);
+    options
+        .set_keep_alive(keep_alive)
+        .set_clean_session(clean_session)
+        .set_connection_timeout(connect_timeout)
+        .set_credentials(conn.user.clone(), conn.password.clone());
+    let client_auth = load_client_auth(name, conn)?;
+    match (&conn.tls_server_name, conn.tls) {
+        (Some(_), false) => {
+            return Err(anyhow::anyhow!(
+                "{} tls_server_name requires tls to be enabled.",
+                name
+            ));
+        }
+        (Some(server_name), true) => {
+            let ca = load_ca_chain(conn)?;
+            let config = tls_client_config(name, server_name, &ca, client_auth)?;
+            options.set_tls_client_config(Arc::new(config));
+        }
+        (None, tls) => {
+            if tls {
+                options.set_ca(load_ca_chain(conn)?);
+            }
+            if let Some((cert, key)) = client_auth {
+                options.set_client_auth(cert, key);
+            }
+        }
+    }
+    log::info!("Connecting to {} {}:{}", name, &conn.host, port);
+    Ok(options)
+}
+
+/// The retained `lwt_payload` the source broker should publish if the bridge dies.
+fn last_will(config: &Config) -> Option<rumqttc::LastWill> {
+    config.lwt_topic.as_ref().map(|topic| {
+        rumqttc::LastWill::new(topic, config.lwt_payload.as_str(), QoS::AtLeastOnce, true)
+    })
+}
+
+/// Connection state shared between the event loops and the health endpoint.
+#[derive(Debug)]
+struct HealthState {
+    /// Unix timestamp in seconds of the last event from the source broker, including pings.
+    last_source_event: AtomicU64,
+    source_connected: AtomicBool,
+    /// One flag per target, in config order.
+    targets_connected: Vec<AtomicBool>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl HealthState {
+    fn new(targets: usize) -> Self {
+        HealthState {
+            last_source_event: AtomicU64::new(0),
+            source_connected: AtomicBool::new(false),
+            targets_connected: (0..targets).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    fn touch_source(&self) {
+        self.last_source_event.store(unix_now(), Ordering::SeqCst);
+    }
+
+    fn is_healthy(&self, now: u64, stale_secs: u64) -> bool {
+        let last = self.last_source_event.load(Ordering::SeqCst);
+        self.targets_connected
+            .iter()
+            .all(|connected| connected.load(Ordering::SeqCst))
+            && now.saturating_sub(last) <= stale_secs
+    }
+}
+
+/// E.g. `Heartbeat: source connected, target disconnected.`
+fn heartbeat_line(target_names: &[String], health: &HealthState) -> String {
+    let status = |connected: &AtomicBool| {
+        if connected.load(Ordering::SeqCst) {
+            "connected"
+        } else {
+            "disconnected"
+        }
+    };
+    let mut line = format!("Heartbeat: source {}", status(&health.source_connected));
+    for (name, connected) in target_names.iter().zip(&health.targets_connected) {
+        line.push_str(&format!(", {} {}", name, status(connected)));
+    }
+    line.push('.');
+    line
+}
+
+/// Emit a heartbeat every `interval` until shutdown. The connection gauges are sent again with
+/// it, in case the collector missed a change.
+async fn heartbeat(
+    interval: Duration,
+    target_names: Vec<String>,
+    health: Arc<HealthState>,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let gauge = |connected: &AtomicBool| {
+        if connected.load(Ordering::SeqCst) {
+            1.0
+        } else {
+            0.0
+        }
+    };
+    loop {
+        tokio::time::delay_for(interval).await;
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        log::info!("{}", heartbeat_line(&target_names, &health));
+        metrics.incr("heartbeat");
+        metrics.gauge("source_connected", gauge(&health.source_connected));
+        for (name, connected) in target_names.iter().zip(&health.targets_connected) {
+            metrics.gauge(&format!("{}_connected", name), gauge(connected));
+        }
+    }
+}
+
+/// The full HTTP response for a request line like `GET /healthz HTTP/1.1`.
+fn health_response(request_line: &str, healthy: bool) -> &'static str {
+    match request_line.split_whitespace().nth(1) {
+        Some("/healthz") if healthy => "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nok\n",
+        Some("/healthz") => {
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 12\r\n\r\nunavailable\n"
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 10\r\n\r\nnot found\n",
+    }
+}
+
+/// How long a health check client gets to send its request or take the response. Requests are
+/// handled one at a time, so a client that connects and goes quiet would block the rest.
+const HEALTH_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn handle_health_request(
+    stream: TcpStream,
+    health: &HealthState,
+    stale_secs: u64,
+) -> Result<(), Error> {
+    stream.set_read_timeout(Some(HEALTH_REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(HEALTH_REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let response = health_response(&request_line, health.is_healthy(unix_now(), stale_secs));
+    (&stream).write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// Serve the health endpoint on a background thread. This is only meant for probes, so requests
+/// are handled one at a time.
+fn spawn_health_server(addr: &str, health: Arc<HealthState>, stale_secs: u64) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("Failed to bind health_listen {}", addr))?;
+    log::info!("Serving health checks on {}.", addr);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(Error::from)
+                .and_then(|s| handle_health_request(s, &health, stale_secs));
+            if let Err(e) = result {
+                log::warn!("Health check request failed: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+const PUBLISH_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Anything the bridge can publish to, so the publish path can be tested without a broker.
+trait Publisher {
+    async fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), Error>;
+}
+
+/// The target request channel was full, i.e. the target broker isn't keeping up.
+#[derive(Debug)]
+struct TargetBackpressure;
+
+impl std::fmt::Display for TargetBackpressure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "target request channel is full")
+    }
+}
+
+impl std::error::Error for TargetBackpressure {}
+
+/// What each unconfirmed QoS 2 publish to one target sent. rumqttc numbers publishes as they go
+/// out, in the order they were queued, and reports the id in an `Outgoing::Publish`; so like
+/// `Subscriptions` the ids are matched up in send order.
+#[derive(Debug, Default)]
+struct Deliveries {
+    /// `<payload> to <topic>` for QoS 2 publishes queued but not sent yet, `None` for the rest.
+    unsent: VecDeque<Option<String>>,
+    /// QoS 1/2 publishes sent but not acked yet, which rumqttc sends again after a reconnect.
+    unacked: HashSet<u16>,
+    /// How many of the next `Outgoing::Publish` are those resends rather than queued publishes.
+    resending: usize,
+    /// `<payload> to <topic>` by packet id, until the PUBCOMP arrives.
+    unconfirmed: HashMap<u16, String>,
+}
+
+impl Deliveries {
+    /// A publish went out as `pkid`, 0 for QoS 0.
+    fn sent(&mut self, pkid: u16) {
+        if self.resending > 0 && self.unacked.contains(&pkid) {
+            self.resending -= 1;
+            return;
+        }
+        let delivery = self.unsent.pop_front().flatten();
+        if pkid == 0 {
+            return;
+        }
+        self.unacked.insert(pkid);
+        if let Some(delivery) = delivery {
+            self.unconfirmed.insert(pkid, delivery);
+        }
+    }
+
+    /// The PUBACK or PUBREC for `pkid` arrived, so rumqttc won't send it again.
+    fn acked(&mut self, pkid: u16) {
+        self.unacked.remove(&pkid);
+    }
+
+    /// rumqttc sends the unacked publishes again first thing on a new connection.
+    fn reconnected(&mut self) {
+        self.resending = self.unacked.len();
+    }
+
+    /// The PUBCOMP for `pkid` arrived, returning what that publish sent.
+    fn confirmed(&mut self, pkid: u16) -> Option<String> {
+        self.unconfirmed.remove(&pkid)
+    }
+}
+
+/// A publish for the targets, sent right away or held back in an `OfflineQueue`.
+#[derive(Debug, Clone, PartialEq)]
+struct TargetPublish {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    payload: Vec<u8>,
+}
+
+/// Publishes waiting for a target to reconnect, see `offline_queue_size`.
+#[derive(Debug)]
+struct OfflineQueue {
+    capacity: usize,
+    publishes: VecDeque<TargetPublish>,
+    /// From the reconnect until the queue is empty again. New publishes keep queueing meanwhile,
+    /// so they can't overtake older ones.
+    flushing: bool,
+}
+
+impl OfflineQueue {
+    fn new(capacity: usize) -> Self {
+        OfflineQueue {
+            capacity,
+            publishes: VecDeque::new(),
+            flushing: false,
+        }
+    }
+
+    /// Queue `publish`, returning the oldest one if it had to make room.
+    fn push(&mut self, publish: TargetPublish) -> Option<TargetPublish> {
+        let dropped = if self.publishes.len() >= self.capacity {
+            self.publishes.pop_front()
+        } else {
+            None
+        };
+        self.publishes.push_back(publish);
+        dropped
+    }
+}
+
+/// Hands publishes to the target event loop without waiting for room in its request channel,
+/// so a backed up target becomes a retryable `TargetBackpressure` error instead of stalling the
+/// source side.
+#[derive(Clone)]
+struct TargetPublisher {
+    requests: rumqttc::Sender<Request>,
+    deliveries: Arc<Mutex<Deliveries>>,
+    queue: Option<Arc<Mutex<OfflineQueue>>>,
+}
+
+impl TargetPublisher {
+    fn deliveries(&self) -> MutexGuard<'_, Deliveries> {
+        self.deliveries.lock().expect("Deliveries lock poisoned")
+    }
+
+    /// Hand the publish to the event loop and line it up in `deliveries` for `drive_target` to
+    /// match with its packet id. Both happen under the lock, so they stay in the same order.
+    fn try_publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), rumqttc::TrySendError<Request>> {
+        let mut publish = rumqttc::Publish::new(topic, qos, payload);
+        publish.retain = retain;
+        let delivery = if qos == QoS::ExactlyOnce {
+            Some(format!("{} to {}", String::from_utf8_lossy(payload), topic))
+        } else {
+            None
+        };
+        let mut deliveries = self.deliveries();
+        self.requests.try_send(Request::Publish(publish))?;
+        deliveries.unsent.push_back(delivery);
+        Ok(())
+    }
+
+    /// Queue `publish` instead of sending it if the target isn't `connected` or still flushing
+    /// its queue. `None` if it should be sent now, otherwise whether an older one was dropped.
+    fn hold_back(&self, connected: bool, publish: TargetPublish) -> Option<Option<TargetPublish>> {
+        let mut queue = self
+            .queue
+            .as_ref()?
+            .lock()
+            .expect("Offline queue lock poisoned");
+        if connected && !queue.flushing {
+            return None;
+        }
+        Some(queue.push(publish))
+    }
+
+    /// Like `publish`, but waits for room in the request channel. For startup, when nothing is
+    /// waiting on the bridge yet.
+    async fn publish_waiting(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        loop {
+            match self.try_publish(topic, qos, retain, payload) {
+                Ok(()) => return Ok(()),
+                Err(rumqttc::TrySendError::Full(_)) => {
+                    tokio::time::delay_for(TARGET_FULL_POLL_INTERVAL).await
+                }
+                Err(rumqttc::TrySendError::Closed(_)) => {
+                    return Err(anyhow::anyhow!("target event loop has stopped"))
+                }
+            }
+        }
+    }
+}
+
+/// How often `publish_waiting` checks for room in a full request channel.
+const TARGET_FULL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl Publisher for TargetPublisher {
+    async fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        self.try_publish(topic, qos, retain, payload)
+            .map_err(|e| match e {
+                rumqttc::TrySendError::Full(_) => TargetBackpressure.into(),
+                rumqttc::TrySendError::Closed(_) => {
+                    anyhow::anyhow!("target event loop has stopped")
+                }
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    max_retries: u32,
+    delay: Duration,
+}
+
+/// Publish, retrying up to `max_retries` times with `delay` in between. Returns whether the
+/// message went out; a dropped message is logged and metered rather than treated as fatal.
+async fn publish_with_retry<P: Publisher>(
+    publisher: &mut P,
+    topic: &str,
+    qos: QoS,
+    retain: bool,
+    payload: &[u8],
+    retry: Retry,
+    metrics: &Metrics,
+) -> bool {
+    let Retry { max_retries, delay } = retry;
+    let mut attempt = 0;
+    loop {
+        match publisher.publish(topic, qos, retain, payload).await {
+            Ok(()) => return true,
+            Err(e) if attempt < max_retries => {
+                attempt += 1;
+                if e.is::<TargetBackpressure>() {
+                    log::warn!("Target is backed up, delaying publish to {}.", topic);
+                    metrics.incr("target_backpressure");
+                }
+                log::warn!(
+                    "Publishing to {} failed, retry {}/{}: {:?}",
+                    topic,
+                    attempt,
+                    max_retries,
+                    e
+                );
+                metrics.incr("publish_retry");
+                tokio::time::delay_for(delay).await;
+            }
+            Err(e) => {
+                log::error!(
+                    "Dropping publish to {} after {} retries: {:?}",
+                    topic,
+                    max_retries,
+                    e
+                );
+                metrics.incr("publish_dropped");
+                return false;
+            }
+        }
+    }
+}
+
+/// How far a publish to the targets got. Ordered so the best outcome over all targets is the
+/// largest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PublishOutcome {
+    Failed,
+    /// Waiting in an offline queue, see `offline_queue_size`.
+    Queued,
+    Sent,
+}
+
+/// Send `publish` to every target, or queue it for those that are offline. `Sent` as soon as
+/// one target got it, the others are already retried by `publish_with_retry`.
+async fn publish_to_targets(
+    names: &[String],
+    publishers: &mut [TargetPublisher],
+    health: &HealthState,
+    publish: &TargetPublish,
+    max_retries: u32,
+    metrics: &Metrics,
+) -> PublishOutcome {
+    let mut outcome = PublishOutcome::Failed;
+    for (index, (name, publisher)) in names.iter().zip(publishers.iter_mut()).enumerate() {
+        let connected = health.targets_connected[index].load(Ordering::SeqCst);
+        if let Some(dropped) = publisher.hold_back(connected, publish.clone()) {
+            log::info!(
+                "Queued {} to {} until {} reconnects.",
+                String::from_utf8_lossy(&publish.payload),
+                publish.topic,
+                name
+            );
+            metrics.incr_sampled("queued");
+            if let Some(dropped) = dropped {
+                log::warn!(
+                    "Offline queue for {} is full, dropped {} to {}.",
+                    name,
+                    String::from_utf8_lossy(&dropped.payload),
+                    dropped.topic
+                );
+                metrics.incr("queue_overflow");
+            }
+            outcome = outcome.max(PublishOutcome::Queued);
+            continue;
+        }
+        let sent = publish_with_retry(
+            publisher,
+            &publish.topic,
+            publish.qos,
+            publish.retain,
+            &publish.payload,
+            Retry {
+                max_retries,
+                delay: PUBLISH_RETRY_DELAY,
+            },
+            metrics,
+        )
+        .await;
+        if sent {
+            metrics.incr_sampled(&format!("publish_target.{}", name));
+            metrics.count_sampled("bytes_published", publish.payload.len());
+            outcome = PublishOutcome::Sent;
+        }
+    }
+    outcome
+}
+
+/// The source request channel was full. `run` polls the source event loop itself, so waiting
+/// for room from there would stall it for good.
+#[derive(Debug)]
+struct SourceBackpressure;
+
+impl std::fmt::Display for SourceBackpressure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "source request channel is full")
+    }
+}
+
+impl std::error::Error for SourceBackpressure {}
+
+/// Hand `request` to the source event loop without waiting for room, like `TargetPublisher`
+/// does for the targets. A full channel drops it and counts that in `source_dropped`.
+fn try_send_source(
+    requests: &rumqttc::Sender<Request>,
+    request: Request,
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    requests.try_send(request).map_err(|e| match e {
+        rumqttc::TrySendError::Full(_) => {
+            metrics.incr("source_dropped");
+            SourceBackpressure.into()
+        }
+        rumqttc::TrySendError::Closed(_) => anyhow::anyhow!("source event loop has stopped"),
+    })
+}
+
+/// Publish on the source through `try_send_source`.
+fn publish_source(
+    requests: &rumqttc::Sender<Request>,
+    topic: &str,
+    qos: QoS,
+    retain: bool,
+    payload: &[u8],
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    let mut publish = rumqttc::Publish::new(topic, qos, payload);
+    publish.retain = retain;
+    try_send_source(requests, Request::Publish(publish), metrics)
+}
+
+/// Subscribe to every topic. One that doesn't fit in the request channel is retried after a
+/// `Subscriptions::retry_later` delay, so a backed up source doesn't take the bridge down. A
+/// stopped event loop won't come back though, so that one is returned.
+fn subscribe_with_retry(
+    requests: &rumqttc::Sender<Request>,
+    subscriptions: &mut Subscriptions,
+    topics: &[String],
+    qos: QoS,
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    for topic in topics {
+        log::info!("Connected to source, subscribing to {}.", topic);
+        let subscribe = Request::Subscribe(rumqttc::Subscribe::new(topic.as_str(), qos));
+        match try_send_source(requests, subscribe, metrics) {
+            Ok(()) => subscriptions.queued(topic.clone()),
+            Err(e) if e.is::<SourceBackpressure>() => {
+                let delay = subscriptions.retry_later(topic.clone());
+                log::warn!(
+                    "Subscribing to {} failed, retrying in {:?}: {:?}",
+                    topic,
+                    delay,
+                    e
+                );
+                metrics.incr("subscribe_retry");
+            }
+            Err(e) => return Err(e.context(format!("Subscribing to {} failed", topic))),
+        }
+    }
+    Ok(())
+}
+
+/// Matches SUBACKs to the topics subscribed to. rumqttc picks the packet ids as the SUBSCRIBEs
+/// go out, which happens in the order `subscribe_with_retry` queued them.
+#[derive(Debug, Default)]
+struct Subscriptions {
+    unsent: VecDeque<String>,
+    pending: HashMap<u16, String>,
+    /// Refused topics and those that didn't fit in the request channel, with when to subscribe
+    /// again (`None` while that's under way) and how long to wait after the next failure.
+    refused: HashMap<String, (Option<Instant>, Backoff)>,
+}
+
+impl Subscriptions {
+    /// Start over on a new connection, acks for the old one won't come anymore.
+    fn reset(&mut self) {
+        self.unsent.clear();
+        self.pending.clear();
+        self.refused.clear();
+    }
+
+    /// The SUBSCRIBE for `topic` is in the request channel.
+    fn queued(&mut self, topic: String) {
+        self.unsent.push_back(topic);
+    }
+
+    /// Schedule subscribing to `topic` again, returning the delay.
+    fn retry_later(&mut self, topic: String) -> Duration {
+        let (retry_at, backoff) = self
+            .refused
+            .entry(topic)
+            .or_insert_with(|| (None, Backoff::new()));
+        let delay = backoff.next_delay_with_jitter();
+        *retry_at = Some(Instant::now() + delay);
+        delay
+    }
+
+    /// The topics to subscribe to again by `now`.
+    fn due(&mut self, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        for (topic, (retry_at, _)) in &mut self.refused {
+            if retry_at.is_some_and(|at| at <= now) {
+                *retry_at = None;
+                due.push(topic.clone());
+            }
+        }
+        due.sort();
+        due
+    }
+
+    fn sent(&mut self, pkid: u16) {
+        if let Some(topic) = self.unsent.pop_front() {
+            self.pending.insert(pkid, topic);
+        }
+    }
+
+    /// The topic `suback` is for, along with the QoS granted or `None` if it was refused.
+    fn acked(&mut self, suback: &SubAck) -> Option<(String, Option<QoS>)> {
+        let topic = self.pending.remove(&suback.pkid)?;
+        let granted = match suback.return_codes.first() {
+            Some(SubscribeReturnCodes::Success(qos)) => Some(*qos),
+            Some(SubscribeReturnCodes::Failure) | None => None,
+        };
+        if granted.is_some() {
+            self.refused.remove(&topic);
+        }
+        Some((topic, granted))
+    }
+}
+
+/// Publish the bridge's retained status to `lwt_topic`.
+fn publish_status(
+    requests: &rumqttc::Sender<Request>,
+    topic: &str,
+    payload: &str,
+    metrics: &Metrics,
+) {
+    let published = publish_source(
+        requests,
+        topic,
+        QoS::AtLeastOnce,
+        true,
+        payload.as_bytes(),
+        metrics,
+    );
+    if let Err(e) = published {
+        log::warn!("Publishing status to {} failed: {:?}", topic, e);
+    }
+}
+
+/// Confirm a forwarded command on the source broker. On/off switches report `1`/`0`, so a
+/// `toggle` shows up as the state it resolved to; dimmers report the payload as received.
+fn report_state(
+    requests: &rumqttc::Sender<Request>,
+    state_topic: &str,
+    qos: QoS,
+    payload: &str,
+    state: Option<bool>,
+    metrics: &Metrics,
+) {
+    let state = match state {
+        Some(true) => "1",
+        Some(false) => "0",
+        None => payload.trim(),
+    };
+    if let Err(e) = publish_source(requests, state_topic, qos, true, state.as_bytes(), metrics) {
+        log::warn!("Reporting state to {} failed: {:?}", state_topic, e);
+    }
+}
+
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What `state_file` keeps across restarts: the last state and code sent per switch, for
+/// toggling and `suppress_duplicate_states`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct SavedState {
+    #[serde(default)]
+    states: HashMap<String, bool>,
+    #[serde(default)]
+    codes: HashMap<String, String>,
+}
+
+/// A missing or unreadable state file just means starting without any known states.
+fn load_saved_state(path: &str) -> SavedState {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!("Ignoring corrupt state file {}: {}", path, e);
+            SavedState::default()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("No state file at {} yet, starting fresh.", path);
+            SavedState::default()
+        }
+        Err(e) => {
+            log::warn!("Couldn't read state file {}, starting fresh: {}", path, e);
+            SavedState::default()
+        }
+    }
+}
+
+/// Written to a temporary file first so a crash mid-write can't leave a truncated state file.
+fn write_saved_state(path: &str, state: &SavedState) -> Result<(), Error> {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_string(state)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn save_state(path: Option<&str>, state: &SavedState) {
+    if let Some(path) = path {
+        if let Err(e) = write_saved_state(path, state) {
+            log::warn!("Saving state to {} failed: {:?}", path, e);
+        }
+    }
+}
+
+/// The `audit_log_path` line for a dropped message. Payloads that aren't UTF-8 go in
+/// `payload_base64` instead of `payload`.
+fn audit_record(timestamp: &str, topic: &str, payload: &[u8], reason: &str) -> serde_json::Value {
+    let mut record = serde_json::json!({
+        "timestamp": timestamp,
+        "topic": topic,
+        "reason": reason,
+    });
+    match std::str::from_utf8(payload) {
+        Ok(payload) => record["payload"] = payload.into(),
+        Err(_) => record["payload_base64"] = base64::encode(payload).into(),
+    }
+    record
+}
+
+/// Where `audit_record`s go, see `audit_log_path`.
+enum AuditLog {
+    File { path: String, file: fs::File },
+    Noop,
+}
+
+impl AuditLog {
+    fn open(path: Option<&str>) -> Result<AuditLog, Error> {
+        match path {
+            Some(path) => {
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open audit log {}", path))?;
+                Ok(AuditLog::File {
+                    path: path.to_string(),
+                    file,
+                })
+            }
+            None => Ok(AuditLog::Noop),
+        }
+    }
+
+    /// Failing to write is only logged, it's no reason to stop bridging.
+    fn record(&mut self, topic: &str, payload: &[u8], reason: &str) {
+        if let AuditLog::File { path, file } = self {
+            let timestamp = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            let mut line = audit_record(&timestamp, topic, payload, reason).to_string();
+            line.push('\n');
+            // One write per line, so lines from an earlier run or a rotation stay whole.
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                log::warn!("Writing to audit log {} failed: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Between the codes of an `on`/`off` list, long enough for a transmitter to finish sending.
+const DEFAULT_CODE_GAP: Duration = Duration::from_millis(100);
+
+const STARTUP_TEST_GAP: Duration = Duration::from_secs(1);
+
+/// Send the `startup_test_switch` codes with a gap in between, so the transmitter can be seen
+/// (or heard) switching on and off.
+#[derive(Clone)]
+struct StartupTest {
+    switch: String,
+    topic: String,
+    /// Payloads ready to publish, `on` first.
+    payloads: Vec<String>,
+    qos: QoS,
+    retain: bool,
+    retry: Retry,
+}
+
+async fn startup_test(mut publisher: TargetPublisher, test: StartupTest, metrics: Arc<Metrics>) {
+    let StartupTest {
+        switch,
+        topic,
+        payloads,
+        qos,
+        retain,
+        retry,
+    } = test;
+    for (i, payload) in payloads.iter().enumerate() {
+        if i > 0 {
+            tokio::time::delay_for(STARTUP_TEST_GAP).await;
+        }
+        let published = publish_with_retry(
+            &mut publisher,
+            &topic,
+            qos,
+            retain,
+            payload.as_bytes(),
+            retry,
+            &metrics,
+        )
+        .await;
+        if !published {
+            log::error!("Startup test of {} failed sending {}.", switch, payload);
+            metrics.incr("startup_test_failed");
+            return;
+        }
+    }
+    log::info!(
+        "Startup test of {} sent {} to {}.",
+        switch,
+        payloads.join(", "),
+        topic
+    );
+    metrics.incr("startup_test");
+}
+
+/// Sleep before letting the event loop reconnect. rumqttc reconnects on the next poll after an
+/// error, so without this a dead broker would be retried in a tight loop.
+async fn wait_for_reconnect(name: &str, backoff: &mut Backoff, metrics: &Metrics) {
+    let delay = backoff.next_delay_with_jitter();
+    log::warn!("Reconnecting to {} in {:?}.", name, delay);
+    metrics.incr("reconnect");
+    tokio::time::delay_for(delay).await;
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() -> Result<(), Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+/// Reload the switch table from `path` on every SIGHUP.
+async fn reload_on_hangup(
+    mut hangup: tokio::signal::unix::Signal,
+    path: String,
+    scenes: Vec<SceneConfig>,
+    switches: SharedSwitches,
+    metrics: Arc<Metrics>,
+) {
+    while hangup.recv().await.is_some() {
+        reload_and_log(&path, &scenes, &switches, &metrics);
+    }
+}
+
+fn reload_and_log(
+    path: &str,
+    scenes: &[SceneConfig],
+    switches: &SharedSwitches,
+    metrics: &Metrics,
+) {
+    match reload_switches(path, scenes, switches) {
+        Ok(count) => {
+            log::info!("Reloaded {} switches from {}.", count, path);
+            metrics.incr("reload");
+        }
+        Err(e) => {
+            log::error!(
+                "Keeping the current switches, reloading {} failed: {:#}",
+                path,
+                e
+            );
+            metrics.incr("reload_failed");
+        }
+    }
+}
+
+/// What the bridge was told through `control_topic` and what it has done since starting,
+/// reported by `stats`.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+struct ControlState {
+    paused: bool,
+    /// Messages received on the source, not counting control commands.
+    received: u64,
+    /// Messages sent on to at least one target, translated or passed through.
+    forwarded: u64,
+    paused_dropped: u64,
+}
+
+/// What `run` still has to do after a control command was applied to `ControlState`.
+#[derive(Debug, PartialEq, Eq)]
+enum ControlAction {
+    Paused,
+    Resumed,
+    Reload,
+    /// Publish this to `control_reply_topic`.
+    Reply(String),
+}
+
+impl ControlState {
+    fn dispatch(&mut self, command: &str) -> Result<ControlAction, String> {
+        match command.trim().to_ascii_lowercase().as_str() {
+            "pause" => {
+                self.paused = true;
+                Ok(ControlAction::Paused)
+            }
+            "resume" => {
+                self.paused = false;
+                Ok(ControlAction::Resumed)
+            }
+            "reload" => Ok(ControlAction::Reload),
+            "stats" => Ok(ControlAction::Reply(
+                serde_json::to_string(self).expect("Control state is always serializable"),
+            )),
+            _ => Err(format!("Unknown control command {:?}.", command)),
+        }
+    }
+}
+
+/// How long to wait for pending messages to go out after a shutdown signal.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keep polling until our DISCONNECT went out, so everything queued before it is flushed too.
+async fn drain_until_disconnect(eventloop: &mut EventLoop) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            // Nothing left to flush without a connection.
+            Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Poll `eventloop`, giving up on a connection attempt after its connection timeout. rumqttc
+/// only bounds the MQTT handshake, the TCP/TLS connect before it can hang for minutes on an
+/// unroutable host.
+async fn poll_connecting(
+    eventloop: &mut EventLoop,
+    connected: bool,
+) -> Result<Event, ConnectionError> {
+    if connected {
+        return eventloop.poll().await;
+    }
+    let timeout = Duration::from_secs(eventloop.options.connection_timeout());
+    tokio::time::timeout(timeout, eventloop.poll())
+        .await
+        .unwrap_or_else(|elapsed| Err(ConnectionError::Timeout(elapsed)))
+}
+
+/// Whether a connection failure needs someone to fix the config or will likely pass by itself.
+#[derive(Debug, PartialEq, Eq)]
+enum FailureKind {
+    /// The broker refused the CONNECT, e.g. for a wrong password or a banned client id.
+    Auth,
+    /// Anything else, from an unreachable host to a dropped connection.
+    Network,
+}
+
+fn classify_connection_error(error: &ConnectionError) -> FailureKind {
+    match error {
+        // rumqttc only hands the CONNACK return code on as part of the message.
+        ConnectionError::Io(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            let message = e.to_string();
+            if message.starts_with("Broker rejected") && !message.contains("ServiceUnavailable") {
+                FailureKind::Auth
+            } else {
+                FailureKind::Network
+            }
+        }
+        _ => FailureKind::Network,
+    }
+}
+
+/// Log a failed poll, keeping a broker that refused us apart from one that never answered and
+/// from plain network trouble, and count it as `auth_error` or `network_error`.
+fn log_connection_error(
+    name: &str,
+    error: &ConnectionError,
+    options: &MqttOptions,
+    metrics: &Metrics,
+) {
+    match (classify_connection_error(error), error) {
+        (FailureKind::Auth, e) => {
+            log::error!(
+                "{} refused the connection, check user, password and client_id: {}",
+                name,
+                e
+            );
+            metrics.incr("auth_error");
+        }
+        (FailureKind::Network, ConnectionError::Timeout(_)) => {
+            let (host, port) = options.broker_address();
+            log::error!(
+                "Timed out connecting to {} {}:{} after {}s.",
+                name,
+                host,
+                port,
+                options.connection_timeout()
+            );
+            metrics.incr(&format!("{}_connect_timeout", name));
+            metrics.incr("network_error");
+        }
+        (FailureKind::Network, e) => {
+            log::error!("Connection error on {}: {:?}", name, e);
+            metrics.incr("network_error");
+        }
+    }
+}
+
+/// Send everything queued while `name` was disconnected, oldest first. Run as its own task, the
+/// request channel only makes room while `drive_target` keeps polling.
+async fn flush_offline_queue(name: String, publisher: TargetPublisher, metrics: Arc<Metrics>) {
+    let queue = match &publisher.queue {
+        Some(queue) => queue,
+        None => return,
+    };
+    let mut flushed = 0;
+    loop {
+        let next = {
+            let mut queue = queue.lock().expect("Offline queue lock poisoned");
+            match queue.publishes.pop_front() {
+                Some(next) => next,
+                None => {
+                    queue.flushing = false;
+                    break;
+                }
+            }
+        };
+        let sent = publisher
+            .publish_waiting(&next.topic, next.qos, next.retain, &next.payload)
+            .await;
+        if let Err(e) = sent {
+            log::error!("Failed to flush the offline queue to {}: {}", name, e);
+            queue.lock().expect("Offline queue lock poisoned").flushing = false;
+            break;
+        }
+        metrics.count_sampled("bytes_published", next.payload.len());
+        flushed += 1;
+    }
+    log::info!("Sent {} queued publishes to {}.", flushed, name);
+}
+
+/// Resolves once every offline queue has been handed to its target event loop.
+async fn offline_queues_flushed(publishers: &[TargetPublisher]) {
+    let flushed = |publisher: &TargetPublisher| {
+        publisher.queue.as_ref().is_none_or(|queue| {
+            let queue = queue.lock().expect("Offline queue lock poisoned");
+            !queue.flushing && queue.publishes.is_empty()
+        })
+    };
+    while !publishers.iter().all(flushed) {
+        tokio::time::delay_for(TARGET_FULL_POLL_INTERVAL).await;
+    }
+}
+
+/// A code for `send_throttled`, the gap to keep before it and where to report how it went.
+type ThrottledPublish = (
+    TargetPublish,
+    Duration,
+    tokio::sync::oneshot::Sender<PublishOutcome>,
+);
+
+/// Send the codes `run` queues on `publishes` to the targets, at least `min_gap` apart and each
+/// at least its own gap after the one before. Runs as its own task so the gap holds across
+/// messages, not only between the codes of one.
+async fn send_throttled(
+    mut publishes: tokio::sync::mpsc::UnboundedReceiver<ThrottledPublish>,
+    min_gap: Duration,
+    names: Vec<String>,
+    mut publishers: Vec<TargetPublisher>,
+    health: Arc<HealthState>,
+    max_retries: u32,
+    metrics: Arc<Metrics>,
+) {
+    let mut last_sent: Option<Instant> = None;
+    while let Some((publish, gap, done)) = publishes.recv().await {
+        if let Some(last_sent) = last_sent {
+            let due = last_sent + gap.max(min_gap);
+            tokio::time::delay_until(tokio::time::Instant::from_std(due)).await;
+        }
+        last_sent = Some(Instant::now());
+        let outcome = publish_to_targets(
+            &names,
+            &mut publishers,
+            &health,
+            &publish,
+            max_retries,
+            &metrics,
+        )
+        .await;
+        // Nobody is waiting for it anymore when shutting down.
+        let _ = done.send(outcome);
+    }
+}
+
+/// Drive the target connection. Publishes are sent from `run` through the matching
+/// `TargetPublisher`; this only has to keep the connection alive, track its state and log
+/// confirmed QoS 2 deliveries. Only returns an error when giving up on reconnecting.
+#[allow(clippy::too_many_arguments)]
+async fn drive_target(
+    name: String,
+    index: usize,
+    mut eventloop: EventLoop,
+    publisher: TargetPublisher,
+    health: Arc<HealthState>,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+    max_reconnect_attempts: Option<u32>,
+) -> Result<(), Error> {
+    let mut backoff = Backoff::new();
+    let mut reconnects = ReconnectLimit::new(max_reconnect_attempts);
+    loop {
+        let connected = health.targets_connected[index].load(Ordering::SeqCst);
+        let event = poll_connecting(&mut eventloop, connected).await;
+        log::trace!("Processing {} event: {:?}", name, event);
+        match event {
+            Err(_) if shutdown.load(Ordering::SeqCst) => break,
+            Err(e) => {
+                if let ConnectionError::MqttState(StateError::Collision(pkid)) = e {
+                    // rumqttc kept the colliding publish to send again after reconnecting.
+                    publisher.deliveries().sent(pkid);
+                }
+                // Includes the broker rejecting our credentials, which would otherwise only
+                // show up as nothing arriving on the target.
+                log_connection_error(&name, &e, &eventloop.options, &metrics);
+                metrics.incr(&format!("{}_error", name));
+                health.targets_connected[index].store(false, Ordering::SeqCst);
+                metrics.gauge(&format!("{}_connected", name), 0.0);
+                reconnects.failed(&name)?;
+                wait_for_reconnect(&name, &mut backoff, &metrics).await;
+            }
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("Connected to {}.", name);
+                health.targets_connected[index].store(true, Ordering::SeqCst);
+                metrics.gauge(&format!("{}_connected", name), 1.0);
+                backoff.reset();
+                reconnects.reset();
+                publisher.deliveries().reconnected();
+                if let Some(queue) = &publisher.queue {
+                    let mut queue = queue.lock().expect("Offline queue lock poisoned");
+                    if !queue.flushing && !queue.publishes.is_empty() {
+                        queue.flushing = true;
+                        tokio::spawn(flush_offline_queue(
+                            name.clone(),
+                            publisher.clone(),
+                            metrics.clone(),
+                        ));
+                    }
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Publish(pkid))) => publisher.deliveries().sent(pkid),
+            Ok(Event::Incoming(Packet::PubAck(ack))) => publisher.deliveries().acked(ack.pkid),
+            Ok(Event::Incoming(Packet::PubRec(rec))) => publisher.deliveries().acked(rec.pkid),
+            Ok(Event::Incoming(Packet::PubComp(comp))) => {
+                let confirmed = publisher.deliveries().confirmed(comp.pkid);
+                if let Some(delivery) = confirmed {
+                    log::info!("{} confirmed delivery of {}.", name, delivery);
+                    metrics.incr(&format!("{}_confirmed", name));
+                }
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Tag Sentry events with the message being handled, so an error logged meanwhile says which
+/// one. `None` removes a tag again.
+fn set_sentry_tags(tags: &[(&str, Option<&str>)]) {
+    sentry::configure_scope(|scope| {
+        for (key, value) in tags {
+            match value {
+                Some(value) => scope.set_tag(key, value),
+                None => scope.remove_tag(key),
+            }
+        }
+    });
+}
+
+/// What `run` keeps around between publishes from the source.
+struct BridgeState<'a> {
+    config: &'a Config,
+    config_path: Option<String>,
+    metrics: Arc<Metrics>,
+    health: Arc<HealthState>,
+    target_names: &'a [String],
+    target_publishers: Vec<TargetPublisher>,
+    /// Only with `min_send_gap_ms`, otherwise codes are sent right away.
+    throttle: Option<tokio::sync::mpsc::UnboundedSender<ThrottledPublish>>,
+    source_requests: rumqttc::Sender<Request>,
+    shared_switches: SharedSwitches,
+    control_reply_topic: Option<String>,
+    control: ControlState,
+    last_publish: HashMap<String, Instant>,
+    short_topics: HashSet<String>,
+    saved: SavedState,
+    audit_log: AuditLog,
+    /// Set when `once` exits on a publish that is still in an offline queue.
+    awaiting_flush: bool,
+}
+
+/// Handle one publish from the source: a control command, a passthrough, or a command to
+/// translate and forward. Returns whether `once` is done.
+async fn handle_source_publish(state: &mut BridgeState<'_>, p: rumqttc::Publish) -> bool {
+    let BridgeState {
+        config,
+        config_path,
+        metrics,
+        health,
+        target_names,
+        target_publishers,
+        throttle,
+        source_requests,
+        shared_switches,
+        control_reply_topic,
+        control,
+        last_publish,
+        short_topics,
+        saved,
+        audit_log,
+        awaiting_flush,
+    } = state;
+    let received_at = Instant::now();
+    metrics.count_sampled("bytes_received", p.payload.len());
+    if config.report_state
+        && state_topic(&p.topic, config.switch_name_segment).as_deref() == Some(p.topic.as_str())
+    {
+        // Our own state report coming back through the subscription.
+        return false;
+    }
+    let control_topics = config
+        .control_topic
+        .as_deref()
+        .zip(control_reply_topic.as_deref());
+    if let Some((_, reply_topic)) = control_topics.filter(|(topic, _)| *topic == p.topic) {
+        if p.retain {
+            // Would be applied again on every reconnect.
+            log::warn!("Ignoring retained control command on {}.", &p.topic);
+            metrics.incr("control_retained");
+            return false;
+        }
+        let command = String::from_utf8_lossy(&p.payload);
+        match control.dispatch(&command) {
+            Ok(ControlAction::Paused) => {
+                log::info!("Paused, dropping messages until resumed.")
+            }
+            Ok(ControlAction::Resumed) => log::info!("Resumed forwarding."),
+            Ok(ControlAction::Reload) => match &config_path {
+                Some(path) => reload_and_log(path, &config.scenes, shared_switches, metrics),
+                None => {
+                    log::warn!("Can't reload, the config wasn't read from a file.")
+                }
+            },
+            Ok(ControlAction::Reply(reply)) => {
+                let published = publish_source(
+                    source_requests,
+                    reply_topic,
+                    config.source_qos,
+                    false,
+                    reply.as_bytes(),
+                    metrics,
+                );
+                if let Err(e) = published {
+                    log::warn!("Replying on {} failed: {:?}", reply_topic, e);
+                }
+            }
+            Err(e) => {
+                log::warn!("{}", e);
+                metrics.incr("control_unknown");
+            }
+        }
+        return false;
+    }
+    if control_reply_topic.as_deref() == Some(p.topic.as_str()) {
+        // Our own reply coming back through the subscription.
+        return false;
+    }
+    control.received += 1;
+    if control.paused {
+        log::debug!("Paused, dropping message on {}.", &p.topic);
+        metrics.incr_sampled("paused_dropped");
+        control.paused_dropped += 1;
+        audit_log.record(&p.topic, &p.payload, "paused");
+        return false;
+    }
+    if let Some(topic) = config.passthrough_topic(&p.topic) {
+        // Passed through as is, it doesn't have to be text.
+        let payload = String::from_utf8_lossy(&p.payload);
+        if config.dry_run {
+            log::info!("WOULD pass {:?} through to {}", payload, topic);
+            return false;
+        }
+        log::info!(
+            "Passing {:?} on {} through to {}.",
+            payload,
+            &p.topic,
+            topic
+        );
+        metrics.incr_sampled("passthrough");
+        let publish = TargetPublish {
+            topic,
+            qos: config.target_qos,
+            retain: p.retain,
+            payload: p.payload.to_vec(),
+        };
+        let outcome = publish_to_targets(
+            target_names,
+            target_publishers,
+            health,
+            &publish,
+            config.publish_max_retries,
+            metrics,
+        )
+        .await;
+        if outcome == PublishOutcome::Sent {
+            control.forwarded += 1;
+        }
+        if outcome != PublishOutcome::Failed && config.once {
+            log::info!("Forwarded one message, exiting.");
+            *awaiting_flush = outcome == PublishOutcome::Queued;
+            return true;
+        }
+        return false;
+    }
+    if !config.accepts_source_topic(&p.topic) {
+        log::trace!("Skipping {}, not in source_topic_filters.", &p.topic);
+        return false;
+    }
+    let extracted;
+    let raw_payload: &[u8] = match &config.payload_json_path {
+        Some(path) => match json_field(&p.payload, path) {
+            Some(value) => {
+                extracted = value;
+                extracted.as_bytes()
+            }
+            None => {
+                log::warn!("No {} in payload on {}, using it as is.", path, &p.topic);
+                metrics.incr("invalid_payload");
+                &p.payload
+            }
+        },
+        None => &p.payload,
+    };
+    let messages = match expand_scene(
+        &config.scenes,
+        &p.topic,
+        raw_payload,
+        config.switch_name_segment,
+    ) {
+        Some(SceneExpansion::Members(members)) => {
+            log::info!("Activating the scene on {}.", &p.topic);
+            metrics.incr_sampled("scene");
+            members
+                .into_iter()
+                .map(|(topic, payload)| (topic, payload.as_bytes().to_vec()))
+                .collect()
+        }
+        Some(SceneExpansion::NotActivated { scene }) => {
+            log::info!(
+                "Scene {} only reacts to on, ignoring {:?}.",
+                scene,
+                String::from_utf8_lossy(raw_payload)
+            );
+            metrics.incr("unknown_payload");
+            audit_log.record(&p.topic, &p.payload, "unknown_payload");
+            return false;
+        }
+        None => vec![(p.topic.clone(), raw_payload.to_vec())],
+    };
+    // With `once` a scene still goes out as a whole.
+    let mut exiting = false;
+    for (topic, raw_payload) in messages {
+        let raw_payload = &raw_payload[..];
+        let switch_configs = shared_switches
+            .read()
+            .expect("Switch table lock poisoned")
+            .clone();
+        let translated = match handle_publish(
+            &topic,
+            raw_payload,
+            config.switch_name_segment,
+            &switch_configs,
+            &config.target_topic,
+            &config.topic_rules(),
+            &saved.states,
+        ) {
+            Ok(translated) => translated,
+            Err(e) => {
+                log::warn!("Ignoring non-UTF8 payload on {}: {}", &topic, e);
+                metrics.incr("invalid_payload");
+                audit_log.record(&p.topic, &p.payload, "invalid_utf8");
+                continue;
+            }
+        };
+        let payload = String::from_utf8_lossy(raw_payload);
+        set_sentry_tags(&[
+            ("topic", Some(&topic)),
+            (
+                "switch",
+                translated.translation().map(|t| t.switch.name.as_str()),
+            ),
+            ("payload", Some(&payload)),
+        ]);
+        log::info!("Received {:#?}, translated to {:#?}.", payload, translated);
+        let debounced = translated.translation().is_some_and(|t| {
+            is_debounced(
+                t.switch.debounce,
+                last_publish.get(&t.switch.name).copied(),
+                received_at,
+            )
+        });
+        let deduped = translated.translation().is_some_and(|t| {
+            config.suppress_duplicate_states
+                && saved.codes.get(&t.switch.name) == Some(&t.joined_codes())
+        });
+        if let (Some(t), false, false) = (translated.translation(), debounced, deduped) {
+            last_publish.insert(t.switch.name.clone(), received_at);
+        }
+        match translated {
+            TranslateResult::Publish(t) if debounced => {
+                log::info!(
+                    "Dropping {} for {}, sent too recently.",
+                    t.joined_codes(),
+                    &t.switch.name
+                );
+                metrics.incr_sampled("debounced");
+            }
+            TranslateResult::Publish(t) if deduped => {
+                log::debug!(
+                    "Not resending unchanged {} for {}.",
+                    t.joined_codes(),
+                    &t.switch.name
+                );
+                metrics.incr_sampled("deduped");
+            }
+            TranslateResult::Publish(t) if config.dry_run => {
+                for code in &t.codes {
+                    let payload = target_payload(code, t.state, t.switch, config);
+                    log::info!("WOULD publish {} to {}", payload, &t.topic);
+                }
+                // Nothing was sent, so `saved` stays as it is.
+                if config.once {
+                    exiting = true;
+                }
+            }
+            TranslateResult::Publish(t) => {
+                metrics.incr_sampled("publish");
+                metrics.incr_sampled(&format!("publish.{}", metric_name(&t.switch.name)));
+                let switch = t.switch;
+                let mut outcome = PublishOutcome::Failed;
+                let mut throttled = Vec::new();
+                for (index, code) in t.codes.iter().enumerate() {
+                    let gap = if index > 0 {
+                        switch.code_gap.unwrap_or(DEFAULT_CODE_GAP)
+                    } else {
+                        Duration::ZERO
+                    };
+                    let publish = TargetPublish {
+                        topic: t.topic.clone(),
+                        qos: target_qos(switch, config),
+                        retain: target_retain(switch, config),
+                        payload: target_payload(code, t.state, switch, config).into_bytes(),
+                    };
+                    if let Some(throttle) = &throttle {
+                        let (done, result) = tokio::sync::oneshot::channel();
+                        if throttle.send((publish, gap, done)).is_ok() {
+                            throttled.push(result);
+                        }
+                        continue;
+                    }
+                    if index > 0 {
+                        tokio::time::delay_for(gap).await;
+                    }
+                    outcome = outcome.max(
+                        publish_to_targets(
+                            target_names,
+                            target_publishers,
+                            health,
+                            &publish,
+                            config.publish_max_retries,
+                            metrics,
+                        )
+                        .await,
+                    );
+                }
+                for result in throttled {
+                    let throttled = result.await.unwrap_or(PublishOutcome::Failed);
+                    outcome = outcome.max(throttled);
+                }
+                // Only what went out counts as published below, a queued
+                // command may still be dropped.
+                let published = outcome == PublishOutcome::Sent;
+                if published {
+                    // Publishing completes once the request is handed to the
+                    // target event loops, so this catches a backed up target
+                    // connection.
+                    metrics.timer("translate_publish", received_at.elapsed());
+                }
+                let state_topic = state_topic(&topic, config.switch_name_segment)
+                    .filter(|_| published && config.report_state);
+                if let Some(state_topic) = state_topic {
+                    report_state(
+                        source_requests,
+                        &state_topic,
+                        config.source_qos,
+                        &payload,
+                        t.state,
+                        metrics,
+                    );
+                }
+                if published {
+                    control.forwarded += 1;
+                    saved.codes.insert(t.switch.name.clone(), t.joined_codes());
+                }
+                if let (true, Some(state)) = (published, t.state) {
+                    saved.states.insert(t.switch.name.clone(), state);
+                }
+                if outcome != PublishOutcome::Failed && config.once {
+                    exiting = true;
+                    *awaiting_flush |= outcome == PublishOutcome::Queued;
+                }
+            }
+            TranslateResult::UnknownSwitch => {
+                log::debug!("No switch matched {} with payload {:?}.", &topic, payload);
+                metrics.incr_sampled("unmatched");
+                audit_log.record(&p.topic, &p.payload, "unknown_switch");
+            }
+            TranslateResult::UnknownPayload => {
+                log::info!("Unknown payload {:?} on {}.", payload, &topic);
+                metrics.incr("unknown_payload");
+                audit_log.record(&p.topic, &p.payload, "unknown_payload");
+            }
+            TranslateResult::TopicTooShort => {
+                if config.warn_on_short_topic && first_short_topic(short_topics, &topic) {
+                    log::warn!(
+                        "{} has no segment {} to take the switch name from, is \
+                             source_topic_prefix right? Not warning about it again.",
+                        &topic,
+                        config.switch_name_segment
+                    );
+                    metrics.incr("short_topic");
+                } else {
+                    log::debug!(
+                        "{} has no segment {} to take the switch name from.",
+                        &topic,
+                        config.switch_name_segment
+                    );
+                }
+                metrics.incr("topic_too_short");
+                audit_log.record(&p.topic, &p.payload, "topic_too_short");
+            }
+            TranslateResult::NoOffCode { switch } => {
+                log::info!("Switch {} has no off code, not sending anything.", switch);
+                metrics.incr("no_off_code");
+                audit_log.record(&p.topic, &p.payload, "no_off_code");
+            }
+            TranslateResult::NoCrossing { switch } => {
+                log::debug!(
+                    "{} {:?} didn't cross a threshold, leaving it as is.",
+                    switch,
+                    payload
+                );
+                metrics.incr_sampled("no_crossing");
+            }
+        }
+    }
+    if exiting {
+        log::info!(
+            "{} one message, exiting.",
+            if config.dry_run {
+                "Handled"
+            } else {
+                "Forwarded"
+            }
+        );
+    }
+    exiting
+}
+
+/// Bridge until `shutdown_signal` resolves, then disconnect from all brokers. With a
+/// `config_path` a SIGHUP reloads the switches from it.
+async fn run<S>(
+    mut config: Config,
+    metrics: Metrics,
+    shutdown_signal: S,
+    config_path: Option<String>,
+) -> Result<(), Error>
+where
+    S: std::future::Future<Output = Result<(), Error>>,
+{
+    let metrics = Arc::new(metrics);
+    // Neither side is connected until its first CONNACK.
+    metrics.gauge("source_connected", 0.0);
+    let target_names: Vec<_> = config.named_targets().map(|(name, _)| name).collect();
+    for name in &target_names {
+        metrics.gauge(&format!("{}_connected", name), 0.0);
+    }
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let health = Arc::new(HealthState::new(config.targets.len()));
+    if let Some(addr) = &config.health_listen {
+        spawn_health_server(addr, health.clone(), config.health_stale_secs)?;
+    }
+
+    if let Some(secs) = config.heartbeat_interval_secs {
+        tokio::spawn(heartbeat(
+            Duration::from_secs(secs),
+            target_names.clone(),
+            health.clone(),
+            metrics.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    let mut target_clients = Vec::new();
+    let mut target_publishers = Vec::new();
+    let mut target_tasks = Vec::new();
+    for (index, (name, conn)) in config.named_targets().enumerate() {
+        let options = build_mqtt_options(&name, conn)?;
+        let deliveries = Arc::new(Mutex::new(Deliveries::default()));
+        let (client, eventloop) = metrics.time(&format!("{}_connect", name), || {
+            AsyncClient::new(options, conn.mqtt_cap())
+        });
+        let publisher = TargetPublisher {
+            requests: eventloop.handle(),
+            deliveries,
+            queue: config
+                .offline_queue_size
+                .map(|size| Arc::new(Mutex::new(OfflineQueue::new(size)))),
+        };
+        target_publishers.push(publisher.clone());
+        target_tasks.push(tokio::spawn(drive_target(
+            name,
+            index,
+            eventloop,
+            publisher,
+            health.clone(),
+            metrics.clone(),
+            shutdown.clone(),
+            config.max_reconnect_attempts,
+        )));
+        target_clients.push(client);
+    }
+
+    let switch_configs = take_switch_configs(&mut config)?;
+    if config.homeassistant_discovery {
+        // Queued until the target connections are up.
+        for publisher in &target_publishers {
+            publish_discovery(publisher, &switch_configs, &config).await?;
+        }
+    }
+    // `run` only starts once per process, so this is the only test sent.
+    if let Some(switch) = config
+        .startup_test_switch
+        .as_ref()
+        .and_then(|name| switch_configs.get(name))
+    {
+        if let SwitchKind::OnOff { on, off } = &switch.kind {
+            let topic = switch
+                .target_topic
+                .as_deref()
+                .unwrap_or(&config.target_topic);
+            // There's no source topic to append segments from, only the suffix applies.
+            let suffix = config.target_topic_suffix.as_deref().unwrap_or("");
+            let test = StartupTest {
+                switch: switch.name.clone(),
+                topic: format!("{}{}", topic, suffix),
+                payloads: on
+                    .iter()
+                    .map(|code| target_payload(code, Some(true), switch, &config))
+                    .chain(
+                        off.iter()
+                            .flatten()
+                            .map(|code| target_payload(code, Some(false), switch, &config)),
+                    )
+                    .collect(),
+                qos: target_qos(switch, &config),
+                retain: target_retain(switch, &config),
+                retry: Retry {
+                    max_retries: config.publish_max_retries,
+                    delay: PUBLISH_RETRY_DELAY,
+                },
+            };
+            if config.dry_run {
+                for payload in &test.payloads {
+                    log::info!("WOULD publish {} to {}", payload, test.topic);
+                }
+            } else {
+                for publisher in &target_publishers {
+                    tokio::spawn(startup_test(
+                        publisher.clone(),
+                        test.clone(),
+                        metrics.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut source_options = build_mqtt_options("source", &config.source)?;
+    if let Some(will) = last_will(&config) {
+        source_options.set_last_will(will);
+    }
+    let source_cap = config.source.mqtt_cap();
+    let (source_client, mut source_eventloop) = metrics.time("source_connect", || {
+        AsyncClient::new(source_options, source_cap)
+    });
+
+    // Discovery and the startup test above stay with the switches we started with.
+    let shared_switches: SharedSwitches = Arc::new(RwLock::new(Arc::new(switch_configs)));
+    if let Some(path) = config_path.clone() {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        tokio::spawn(reload_on_hangup(
+            signal(SignalKind::hangup())?,
+            path,
+            config.scenes.clone(),
+            shared_switches.clone(),
+            metrics.clone(),
+        ));
+    }
+
+    // Without a gap codes are sent right away, from `handle_source_publish`.
+    let mut throttle = None;
+    let mut throttle_task = None;
+    if let Some(ms) = config.min_send_gap_ms {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        throttle = Some(tx);
+        throttle_task = Some(tokio::spawn(send_throttled(
+            rx,
+            Duration::from_millis(ms),
+            target_names.clone(),
+            target_publishers.clone(),
+            health.clone(),
+            config.publish_max_retries,
+            metrics.clone(),
+        )));
+    }
+
+    let source_topics = config.source_topics();
+    let mut subscriptions = Subscriptions::default();
+    tokio::pin!(shutdown_signal);
+    let mut backoff = Backoff::new();
+    let mut reconnects = ReconnectLimit::new(config.max_reconnect_attempts);
+    let mut reconnect_delay = None;
+    let saved = config
+        .state_file
+        .as_deref()
+        .map(load_saved_state)
+        .unwrap_or_default();
+    let mut last_saved = (saved.clone(), Instant::now());
+    let mut state = BridgeState {
+        config: &config,
+        config_path,
+        metrics: metrics.clone(),
+        health: health.clone(),
+        target_names: &target_names,
+        target_publishers,
+        throttle,
+        source_requests: source_eventloop.handle(),
+        shared_switches,
+        control_reply_topic: config.control_reply_topic(),
+        control: ControlState::default(),
+        last_publish: HashMap::new(),
+        short_topics: HashSet::new(),
+        saved,
+        audit_log: AuditLog::open(config.audit_log_path.as_deref())?,
+        awaiting_flush: false,
+    };
+    loop {
+        let notification = tokio::select! {
+            result = &mut shutdown_signal => {
+                result?;
+                break;
+            }
+            (result, index, _) = futures_util::future::select_all(target_tasks.iter_mut()) => {
+                result??;
+                return Err(anyhow::anyhow!(
+                    "{} event loop stopped unexpectedly.",
+                    target_names[index]
+                ));
+            }
+            notification = async {
+                if let Some(delay) = reconnect_delay.take() {
+                    tokio::time::delay_for(delay).await;
+                }
+                let connected = health.source_connected.load(Ordering::SeqCst);
+                poll_connecting(&mut source_eventloop, connected).await
+            } => notification,
+        };
+        log::trace!("Processing source event: {:?}", notification);
+        if notification.is_ok() {
+            health.touch_source();
+        }
+        // Checked between events rather than on a timer of its own, keep-alive pings make sure
+        // there are some.
+        if last_saved.1.elapsed() >= STATE_SAVE_INTERVAL && state.saved != last_saved.0 {
+            save_state(config.state_file.as_deref(), &state.saved);
+            last_saved = (state.saved.clone(), Instant::now());
+        }
+        if health.source_connected.load(Ordering::SeqCst) {
+            let due = subscriptions.due(Instant::now());
+            subscribe_with_retry(
+                &state.source_requests,
+                &mut subscriptions,
+                &due,
+                config.source_qos,
+                &metrics,
+            )?;
+        }
+        match notification {
+            Err(e) => {
+                log_connection_error("source", &e, &source_eventloop.options, &metrics);
+                health.source_connected.store(false, Ordering::SeqCst);
+                metrics.gauge("source_connected", 0.0);
+                reconnects.failed("source")?;
+                let delay = backoff.next_delay_with_jitter();
+                log::warn!("Reconnecting to source in {:?}.", delay);
+                metrics.incr("reconnect");
+                // Waited for on the next poll so a shutdown signal can still interrupt it.
+                reconnect_delay = Some(delay);
+            }
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // Subscriptions don't survive a clean session, so (re-)subscribe on every connect.
+                health.source_connected.store(true, Ordering::SeqCst);
+                metrics.gauge("source_connected", 1.0);
+                backoff.reset();
+                reconnects.reset();
+                if let Some(topic) = &config.lwt_topic {
+                    publish_status(
+                        &state.source_requests,
+                        topic,
+                        &config.online_payload,
+                        &metrics,
+                    );
+                }
+                subscriptions.reset();
+                subscribe_with_retry(
+                    &state.source_requests,
+                    &mut subscriptions,
+                    &source_topics,
+                    config.source_qos,
+                    &metrics,
+                )?;
+            }
+            Ok(Event::Incoming(Packet::SubAck(suback))) => {
+                metrics.incr("suback");
+                match subscriptions.acked(&suback) {
+                    Some((topic, Some(qos))) => {
+                        log::info!(
+                            "Source confirmed the subscription to {} at {:?}.",
+                            topic,
+                            qos
+                        );
+                    }
+                    Some((topic, None)) => {
+                        let delay = subscriptions.retry_later(topic.clone());
+                        log::error!(
+                            "Source refused the subscription to {}, retrying in {:?}.",
+                            topic,
+                            delay
+                        );
+                        metrics.incr("suback_failure");
+                    }
+                    None => log::debug!("Unexpected SUBACK {}.", suback.pkid),
+                }
+            }
+            Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                // Only our status and state reports are published on the source.
+                log::debug!("Source acknowledged publish {}.", ack.pkid);
+                metrics.incr("puback");
+            }
+            Ok(Event::Incoming(packet)) => {
+                if let Packet::Publish(p) = packet {
+                    let done = handle_source_publish(&mut state, p).await;
+                    // Errors after this one aren't about that message anymore.
+                    set_sentry_tags(&[("topic", None), ("switch", None), ("payload", None)]);
+                    if done {
+                        break;
+                    }
+                }
+            }
+            Ok(Event::Outgoing(event)) => {
+                match event {
+                    Outgoing::PingReq => {
+                        // Ignoring this because it's spammy.
+                    }
+                    Outgoing::Subscribe(pkid) => {
+                        subscriptions.sent(pkid);
+                        log::info!("Outgoing event: {:#?}", event);
+                    }
+                    e => {
+                        log::info!("Outgoing event: {:#?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    // The DISCONNECTs are queued behind any pending publishes, so draining the event loops
+    // lets in-flight messages go out first. statsd sends every metric immediately, so there is
+    // nothing to flush on that side.
+    if state.awaiting_flush {
+        log::info!("Waiting for the offline queues to be sent.");
+        tokio::select! {
+            result = &mut shutdown_signal => result?,
+            (result, index, _) = futures_util::future::select_all(target_tasks.iter_mut()) => {
+                result??;
+                return Err(anyhow::anyhow!(
+                    "{} event loop stopped unexpectedly.",
+                    target_names[index]
+                ));
+            }
+            _ = offline_queues_flushed(&state.target_publishers) => {}
+        }
+    }
+    log::info!("Shutting down, disconnecting.");
+    shutdown.store(true, Ordering::SeqCst);
+    // Closing the channel lets the task send what is still queued and then finish.
+    state.throttle = None;
+    if let Some(task) = throttle_task {
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await {
+            Ok(result) => result?,
+            Err(_) => log::warn!("Timed out sending the remaining throttled codes."),
+        }
+    }
+    // The broker only sends the will on an unclean disconnect.
+    if let Some(topic) = &config.lwt_topic {
+        publish_status(&state.source_requests, topic, &config.lwt_payload, &metrics);
+    }
+    // Drained meanwhile, the DISCONNECT may have to wait for room in the request channel.
+    let disconnected = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        let (sent, ()) = tokio::join!(
+            source_client.disconnect(),
+            drain_until_disconnect(&mut source_eventloop)
+        );
+        sent
+    })
+    .await;
+    match disconnected {
+        Ok(sent) => sent?,
+        Err(_) => log::warn!("Timed out disconnecting from source."),
+    }
+    for client in &target_clients {
+        client.disconnect().await?;
+    }
+    for (name, task) in target_names.iter().zip(target_tasks) {
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await {
+            Ok(result) => result??,
+            Err(_) => log::warn!("Timed out disconnecting from {}.", name),
+        }
+    }
+    // A dry run leaves the state of the real runs alone.
+    if !config.dry_run {
+        save_state(config.state_file.as_deref(), &state.saved);
+    }
+    metrics.gauge("source_connected", 0.0);
+    for name in &target_names {
+        metrics.gauge(&format!("{}_connected", name), 0.0);
+    }
+    log::info!("Shut down cleanly.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prepare_zap_configs() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+
+        let actual = prepare_switch_configs(config.switches).expect("Invalid switches");
+        let mut expected = HashMap::with_capacity(2);
+        expected.insert(
+            "d2777".to_string(),
+            SwitchConfig {
+                name: "d2777".to_string(),
+                kind: SwitchKind::OnOff {
+                    on: vec!["FFFFFFFF0001".to_string()],
+                    off: Some(vec!["FFFFFFFF0010".to_string()]),
+                },
+                target_topic: None,
+                pattern: None,
+                debounce: None,
+                protocol: None,
+                pulselength: None,
+                retain: None,
+                invert: false,
+                qos: None,
+                code_gap: None,
+            },
+        );
+        expected.insert(
+            "d2778".to_string(),
+            SwitchConfig {
+                name: "d2778".to_string(),
+                kind: SwitchKind::OnOff {
+                    on: vec!["FFFFFF0F0001".to_string()],
+                    off: Some(vec!["FFFFF0FF0010".to_string()]),
+                },
+                target_topic: None,
+                pattern: None,
+                debounce: None,
+                protocol: None,
+                pulselength: None,
+                retain: None,
+                invert: false,
+                qos: None,
+                code_gap: None,
+            },
+        );
+
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_filter_switches() {
+        let config_str = include_str!("../config/config.toml.example");
+        let names = |enabled: Option<&[&str]>, disabled: Option<&[&str]>| {
+            let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+            let to_strings = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+            let enabled: Option<Vec<String>> = enabled.map(to_strings);
+            let disabled: Option<Vec<String>> = disabled.map(to_strings);
+            filter_switches(config.switches, enabled.as_deref(), disabled.as_deref())
+                .into_iter()
+                .map(|s| s.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(None, None), vec!["d2777", "d2778"]);
+        assert_eq!(names(Some(&["d2778"]), None), vec!["d2778"]);
+        assert_eq!(names(Some(&[]), None), Vec::<String>::new());
+        assert_eq!(names(None, Some(&["d2778"])), vec!["d2777"]);
+        // Disabled wins over enabled.
+        assert_eq!(
+            names(Some(&["d2777", "d2778"]), Some(&["d2777"])),
+            vec!["d2778"]
+        );
+    }
+
+    #[test]
+    fn test_validate_switch_lists() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&format!(
+            "enabled_switches = [\"d2777\"]\ndisabled_switches = [\"d9999\"]\n{}",
+            config_str
+        ))
+        .expect("Invalid config");
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                "disabled_switches names unknown switch d9999.".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_prepare_switch_configs_rejects_duplicates() {
+        let switch = || SwitchConfig {
+            name: "d2777".to_string(),
+            kind: SwitchKind::OnOff {
+                on: vec!["FFFFFFFF0001".to_string()],
+                off: Some(vec!["FFFFFFFF0010".to_string()]),
+            },
+            target_topic: None,
+            pattern: None,
+            debounce: None,
+            protocol: None,
+            pulselength: None,
+            retain: None,
+            invert: false,
+            qos: None,
+            code_gap: None,
+        };
+
+        let err = prepare_switch_configs(vec![switch(), switch()])
+            .expect_err("Duplicate names must be rejected");
+        assert_eq!(err.to_string(), "Duplicate switch names: d2777");
+    }
+
+    fn translation<'a>(
+        switch: &'a SwitchConfig,
+        topic: &str,
+        code: &str,
+        state: Option<bool>,
+    ) -> Translation<'a> {
+        Translation {
+            switch,
+            topic: topic.to_string(),
+            codes: vec![code.to_string()],
+            state,
+        }
+    }
+
+    #[test]
+    fn test_map_payload_payloads() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        let topic = "gBridge/u1/d2777/onoff";
+
+        for payload in &["1", "ON", "on", "true", " 1\n"] {
+            assert_eq!(
+                map_payload(topic, payload, 2, &switches, "zap", &HashMap::new()),
+                TranslateResult::Publish(translation(
+                    &switches["d2777"],
+                    "zap",
+                    "FFFFFFFF0001",
+                    Some(true)
+                )),
+                "payload {:?}",
+                payload
+            );
+        }
+        for payload in &["0", "OFF", "off", "false", "\toff "] {
+            assert_eq!(
+                map_payload(topic, payload, 2, &switches, "zap", &HashMap::new()),
+                TranslateResult::Publish(translation(
+                    &switches["d2777"],
+                    "zap",
+                    "FFFFFFFF0010",
+                    Some(false)
+                )),
+                "payload {:?}",
+                payload
+            );
+        }
+        assert_eq!(
+            map_payload(topic, "maybe", 2, &switches, "zap", &HashMap::new()),
+            TranslateResult::UnknownPayload
+        );
+    }
+
+    #[test]
+    fn test_handle_publish() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        let no_states = HashMap::new();
+        let handle = |topic, payload| {
+            handle_publish(
+                topic,
+                payload,
+                2,
+                &switches,
+                "zap",
+                &TopicRules::default(),
+                &no_states,
+            )
+        };
+
+        assert_eq!(
+            handle("gBridge/u1/d2778/onoff", b"1"),
+            Ok(TranslateResult::Publish(translation(
+                &switches["d2778"],
+                "zap",
+                "FFFFFF0F0001",
+                Some(true)
+            )))
+        );
+        assert_eq!(
+            handle("gBridge/u1/d9999/onoff", b"1"),
+            Ok(TranslateResult::UnknownSwitch)
+        );
+        assert_eq!(
+            handle("gBridge/u1/d2778/onoff", b"dim"),
+            Ok(TranslateResult::UnknownPayload)
+        );
+        assert_eq!(
+            handle("gBridge/u1/d2778/onoff", b""),
+            Ok(TranslateResult::UnknownPayload)
+        );
+        assert_eq!(
+            handle("gBridge/u1", b"1"),
+            Ok(TranslateResult::TopicTooShort)
+        );
+        assert!(handle("gBridge/u1/d2778/onoff", b"\xff\xfe").is_err());
+    }
+
+    #[test]
+    fn test_render_topic_template() {
+        let topic = "home/rf/d2777/set";
+
+        assert_eq!(
+            render_topic_template("gbridge/{switch}/cmd", topic, 2),
+            Some("gbridge/d2777/cmd".to_string())
+        );
+        assert_eq!(
+            render_topic_template("{segment:0}/{segment:1}/out", topic, 2),
+            Some("home/rf/out".to_string())
+        );
+        assert_eq!(
+            render_topic_template("static/topic", topic, 2),
+            Some("static/topic".to_string())
+        );
+        assert_eq!(render_topic_template("{segment:4}", topic, 2), None);
+        assert_eq!(render_topic_template("{device}", topic, 2), None);
+        assert_eq!(render_topic_template("gbridge/{switch", topic, 2), None);
+    }
+
+    #[test]
+    fn test_handle_publish_target_topic_template() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        let no_states = HashMap::new();
+        let topic_for = |template| {
+            handle_publish(
+                "gBridge/u1/d2777/onoff",
+                b"1",
+                2,
+                &switches,
+                "zap",
+                &TopicRules {
+                    template,
+                    ..TopicRules::default()
+                },
+                &no_states,
+            )
+            .expect("Invalid payload")
+            .translation()
+            .map(|t| t.topic.clone())
+        };
+
+        assert_eq!(topic_for(None), Some("zap".to_string()));
+        assert_eq!(
+            topic_for(Some("gbridge/{switch}/cmd")),
+            Some("gbridge/d2777/cmd".to_string())
+        );
+        // Falls back to the static topic when the template doesn't fit.
+        assert_eq!(topic_for(Some("{segment:9}")), Some("zap".to_string()));
+    }
+
+    #[test]
+    fn test_handle_publish_target_topic_suffix() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let mut switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        switches.get_mut("d2778").unwrap().target_topic = Some("own".to_string());
+        let no_states = HashMap::new();
+        let topic_for = |topic, rules: TopicRules| {
+            handle_publish(topic, b"1", 2, &switches, "zap", &rules, &no_states)
+                .expect("Invalid payload")
+                .translation()
+                .map(|t| t.topic.clone())
+        };
+        let suffix = TopicRules {
+            suffix: Some("/set"),
+            ..TopicRules::default()
+        };
+        let segments = TopicRules {
+            append_segments: true,
+            ..TopicRules::default()
+        };
+
+        assert_eq!(
+            topic_for("gBridge/u1/d2777/onoff", suffix),
+            Some("zap/set".to_string())
+        );
+        assert_eq!(
+            topic_for("gBridge/u1/d2778/onoff", suffix),
+            Some("own/set".to_string())
+        );
+        assert_eq!(
+            topic_for("gBridge/u1/d2777/onoff", segments),
+            Some("zap/onoff".to_string())
+        );
+        assert_eq!(
+            topic_for("gBridge/u1/d2777/kitchen/onoff", segments),
+            Some("zap/kitchen/onoff".to_string())
+        );
+        // Nothing after the switch name, nothing to append.
+        assert_eq!(
+            topic_for("gBridge/u1/d2777", segments),
+            Some("zap".to_string())
+        );
+        let both = TopicRules {
+            template: Some("gbridge/{switch}"),
+            suffix: Some("/set"),
+            append_segments: true,
+        };
+        assert_eq!(
+            topic_for("gBridge/u1/d2777/onoff", both),
+            Some("gbridge/d2777/onoff/set".to_string())
+        );
+    }
+
+    #[test]
+    fn test_map_payload_toggle() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        let topic = "gBridge/u1/d2777/onoff";
+        let mut last_states = HashMap::new();
+
+        // Nothing sent yet, so the first toggle turns the switch on.
+        assert_eq!(
+            map_payload(topic, "toggle", 2, &switches, "zap", &last_states),
+            TranslateResult::Publish(translation(
+                &switches["d2777"],
+                "zap",
+                "FFFFFFFF0001",
+                Some(true)
+            ))
+        );
+
+        last_states.insert("d2777".to_string(), true);
+        assert_eq!(
+            map_payload(topic, "TOGGLE", 2, &switches, "zap", &last_states),
+            TranslateResult::Publish(translation(
+                &switches["d2777"],
+                "zap",
+                "FFFFFFFF0010",
+                Some(false)
+            ))
+        );
+
+        last_states.insert("d2777".to_string(), false);
+        assert_eq!(
+            map_payload(topic, "toggle", 2, &switches, "zap", &last_states),
+            TranslateResult::Publish(translation(
+                &switches["d2777"],
+                "zap",
+                "FFFFFFFF0001",
+                Some(true)
+            ))
+        );
+
+        // Other switches keep their own state.
+        assert_eq!(
+            map_payload(
+                "gBridge/u1/d2778/onoff",
+                "toggle",
+                2,
+                &switches,
+                "zap",
+                &last_states
+            )
+            .translation()
+            .map(|t| t.state),
+            Some(Some(true))
+        );
+    }
+
+    #[test]
+    fn test_map_payload_switch_name_segment() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+
+        assert_eq!(
+            map_payload("rf/d2777", "1", 1, &switches, "zap", &HashMap::new()),
+            TranslateResult::Publish(translation(
+                &switches["d2777"],
+                "zap",
+                "FFFFFFFF0001",
+                Some(true)
+            ))
+        );
+        assert_eq!(
+            map_payload(
+                "home/rf/433/d2778/set",
+                "0",
+                3,
+                &switches,
+                "zap",
+                &HashMap::new()
+            ),
+            TranslateResult::Publish(translation(
+                &switches["d2778"],
+                "zap",
+                "FFFFF0FF0010",
+                Some(false)
+            ))
+        );
+        assert_eq!(
+            map_payload(
+                "home/rf/433/d2778/set",
+                "0",
+                2,
+                &switches,
+                "zap",
+                &HashMap::new()
+            ),
+            TranslateResult::UnknownSwitch
+        );
+    }
+
+    #[test]
+    fn test_map_payload_glob() {
+        #[derive(Deserialize)]
+        struct Switches {
+            switches: Vec<SwitchConfig>,
+        }
+        let config: Switches = toml::from_str(
+            r#"
+            [[switches]]
+            name = "livingroom_*"
+            match = "glob"
+            on = "GLOB_ON"
+            off = "GLOB_OFF"
+
+            [[switches]]
+            name = "livingroom_l*"
+            match = "glob"
+            on = "LAMP_ON"
+            off = "LAMP_OFF"
+
+            [[switches]]
+            name = "livingroom_tv"
+            on = "TV_ON"
+            off = "TV_OFF"
+            "#,
+        )
+        .expect("Invalid switches");
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        let code = |device: &str| {
+            let topic = format!("gBridge/u1/{}/onoff", device);
+            map_payload(&topic, "1", 2, &switches, "zap", &HashMap::new())
+                .translation()
+                .map(Translation::joined_codes)
+        };
+
+        assert_eq!(code("livingroom_fan"), Some("GLOB_ON".to_string()));
+        // Exact names beat any pattern.
+        assert_eq!(code("livingroom_tv"), Some("TV_ON".to_string()));
+        // Both patterns match, the alphabetically first one wins.
+        assert_eq!(code("livingroom_lamp"), Some("GLOB_ON".to_string()));
+        assert_eq!(code("kitchen_lamp"), None);
+
+        let invalid: Result<Switches, _> = toml::from_str(
+            r#"
+            [[switches]]
+            name = "livingroom_[*"
+            match = "glob"
+            on = "ON"
+            off = "OFF"
+            "#,
+        );
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_map_payload_switch_target_topic() {
+        let switches = prepare_switch_configs(vec![SwitchConfig {
+            name: "d2779".to_string(),
+            kind: SwitchKind::OnOff {
+                on: vec!["FFFF0FFF0001".to_string()],
+                off: Some(vec!["FFFF0FFF0010".to_string()]),
+            },
+            target_topic: Some("<user>/feeds/zap-cellar".to_string()),
+            pattern: None,
+            debounce: None,
+            protocol: None,
+            pulselength: None,
+            retain: None,
+            invert: false,
+            qos: None,
+            code_gap: None,
+        }])
+        .expect("Invalid switches");
+
+        assert_eq!(
+            map_payload(
+                "gBridge/u1/d2779/onoff",
+                "1",
+                2,
+                &switches,
+                "zap",
+                &HashMap::new()
+            ),
+            TranslateResult::Publish(translation(
+                &switches["d2779"],
+                "<user>/feeds/zap-cellar",
+                "FFFF0FFF0001",
+                Some(true)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_map_payload_dimmer() {
+        let switch: SwitchConfig = toml::from_str(
+            r#"
+            name = "d3000"
+            type = "dimmer"
+            levels = [
+                { min = 50, code = "FFFF00000050" },
+                { min = 0, code = "FFFF00000000" },
+                { min = 1, code = "FFFF00000001" },
+            ]
+            "#,
+        )
+        .expect("Invalid dimmer config");
+        let switches = prepare_switch_configs(vec![switch]).expect("Invalid switches");
+        let code = |payload| {
+            map_payload(
+                "gBridge/u1/d3000/brightness",
+                payload,
+                2,
+                &switches,
+                "zap",
+                &HashMap::new(),
+            )
+            .translation()
+            .map(Translation::joined_codes)
+        };
+
+        assert_eq!(code("0"), Some("FFFF00000000".to_string()));
+        assert_eq!(code("30"), Some("FFFF00000001".to_string()));
+        assert_eq!(code("50"), Some("FFFF00000050".to_string()));
+        assert_eq!(code("ON"), Some("FFFF00000050".to_string()));
+        assert_eq!(code("off"), Some("FFFF00000000".to_string()));
+        assert_eq!(code("101"), None);
+    }
+
+    #[test]
+    fn test_plain_switch_requires_codes() {
+        let result: Result<SwitchConfig, _> = toml::from_str(
+            r#"
+            name = "d2777"
+            off = "FFFFFFFF0010"
+            "#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_map_payload_code_lists() {
+        let switch: SwitchConfig = toml::from_str(
+            r#"
+            name = "d2777"
+            on = ["FFFFFFFF0001", "FFFFFFFF0001"]
+            off = "FFFFFFFF0010"
+            code_gap_ms = 250
+            "#,
+        )
+        .expect("Invalid switch");
+        assert_eq!(switch.code_gap, Some(Duration::from_millis(250)));
+        let switches = prepare_switch_configs(vec![switch]).expect("Invalid switches");
+        let topic = "gBridge/u1/d2777/onoff";
+
+        let on = map_payload(topic, "1", 2, &switches, "zap", &HashMap::new());
+        let on = on.translation().expect("On didn't translate");
+        assert_eq!(on.codes, vec!["FFFFFFFF0001", "FFFFFFFF0001"]);
+        assert_eq!(on.joined_codes(), "FFFFFFFF0001,FFFFFFFF0001");
+        assert_eq!(
+            map_payload(topic, "0", 2, &switches, "zap", &HashMap::new()),
+            TranslateResult::Publish(translation(
+                &switches["d2777"],
+                "zap",
+                "FFFFFFFF0010",
+                Some(false)
+            ))
+        );
+
+        let empty: Result<SwitchConfig, _> = toml::from_str(
+            r#"
+            name = "d2777"
+            on = "FFFFFFFF0001"
+            off = []
+            "#,
+        );
+        assert!(empty.is_err());
+    }
+
+    #[test]
+    fn test_expand_scene() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&format!(
+            "{}\n{}",
+            config_str,
+            r#"
+            [[scenes]]
+            name = "all_off"
+            actions = [
+                { switch = "d2777", state = "off" },
+                { switch = "d2778", state = "toggle" },
+            ]
+            "#
+        ))
+        .expect("Invalid config");
+        assert_eq!(config.validate(), Ok(()));
+        let expand = |topic, payload: &[u8]| expand_scene(&config.scenes, topic, payload, 2);
+
+        assert_eq!(
+            expand("gBridge/u1/all_off/onoff", b"1"),
+            Some(SceneExpansion::Members(vec![
+                ("gBridge/u1/d2777/onoff".to_string(), "off"),
+                ("gBridge/u1/d2778/onoff".to_string(), "toggle"),
+            ]))
+        );
+        assert_eq!(
+            expand("gBridge/u1/all_off", b"ON"),
+            Some(SceneExpansion::Members(vec![
+                ("gBridge/u1/d2777".to_string(), "off"),
+                ("gBridge/u1/d2778".to_string(), "toggle"),
+            ]))
+        );
+        for payload in &[&b"0"[..], b"dim", &[0xff]] {
+            assert_eq!(
+                expand("gBridge/u1/all_off/onoff", payload),
+                Some(SceneExpansion::NotActivated {
+                    scene: "all_off".to_string()
+                })
+            );
+        }
+        assert_eq!(expand("gBridge/u1/d2777/onoff", b"1"), None);
+        assert_eq!(expand("gBridge/u1", b"1"), None);
+
+        // Each expanded message translates like a command for the member itself.
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        let mut states = HashMap::new();
+        states.insert("d2778".to_string(), true);
+        let codes: Vec<_> = match expand_scene(&config.scenes, "gBridge/u1/all_off/onoff", b"1", 2)
+        {
+            Some(SceneExpansion::Members(members)) => members
+                .iter()
+                .map(|(topic, payload)| {
+                    let translated = map_payload(topic, payload, 2, &switches, "zap", &states);
+                    translated
+                        .translation()
+                        .expect("Member didn't translate")
+                        .codes[0]
+                        .clone()
+                })
+                .collect(),
+            other => panic!("Scene didn't expand: {:?}", other),
+        };
+        assert_eq!(codes, vec!["FFFFFFFF0010", "FFFFF0FF0010"]);
+    }
+
+    #[test]
+    fn test_validate_scenes() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&format!(
+            "disabled_switches = [\"d2778\"]\n{}\n{}",
+            config_str,
+            r#"
+            [[scenes]]
+            name = "d2777"
+            actions = [{ switch = "d3000", state = "on" }]
+
+            [[scenes]]
+            name = "empty"
+            actions = []
+
+            [[scenes]]
+            name = "evening"
+            actions = [{ switch = "d2777", state = "on" }, { switch = "d2778", state = "off" }]
+            "#
+        ))
+        .expect("Invalid config");
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                "Scene name d2777 is used more than once.".to_string(),
+                "Scene d2777 names unknown switch d3000.".to_string(),
+                "Scene empty has no actions.".to_string(),
+                "Scene evening sets switch d2778, which isn't enabled.".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_map_payload_threshold() {
+        let switch: SwitchConfig = toml::from_str(
+            r#"
+            name = "heater"
+            type = "threshold"
+            on = "FFFF0F000001"
+            off = "FFFF0F000010"
+            on_above = 22.5
+            off_below = 20
+            "#,
+        )
+        .expect("Invalid switch");
+        let switches = prepare_switch_configs(vec![switch]).expect("Invalid switches");
+        let topic = "gBridge/u1/heater/temperature";
+        let mut last_states = HashMap::new();
+        let on = TranslateResult::Publish(translation(
+            &switches["heater"],
+            "zap",
+            "FFFF0F000001",
+            Some(true),
+        ));
+        let off = TranslateResult::Publish(translation(
+            &switches["heater"],
+            "zap",
+            "FFFF0F000010",
+            Some(false),
+        ));
+        let unchanged = TranslateResult::NoCrossing {
+            switch: "heater".to_string(),
+        };
+        let reading = |payload: &str, last_states: &HashMap<String, bool>| {
+            map_payload(topic, payload, 2, &switches, "zap", last_states)
+        };
+
+        // Inside the band nothing is known yet, so nothing is sent.
+        assert_eq!(reading("21", &last_states), unchanged);
+        assert_eq!(reading("23", &last_states), on);
+        last_states.insert("heater".to_string(), true);
+        // Still above, back inside the band and exactly on the lower edge keep it on.
+        assert_eq!(reading("24.1", &last_states), unchanged);
+        assert_eq!(reading("21", &last_states), unchanged);
+        assert_eq!(reading("20", &last_states), unchanged);
+        assert_eq!(reading(" 19.9 ", &last_states), off);
+        last_states.insert("heater".to_string(), false);
+        assert_eq!(reading("18", &last_states), unchanged);
+        assert_eq!(reading("22.5", &last_states), unchanged);
+        assert_eq!(reading("22.6", &last_states), on);
+
+        assert_eq!(
+            reading("warm", &last_states),
+            TranslateResult::UnknownPayload
+        );
+        assert_eq!(
+            reading("NaN", &last_states),
+            TranslateResult::UnknownPayload
+        );
+
+        let inverted: Result<SwitchConfig, _> = toml::from_str(
+            r#"
+            name = "heater"
+            type = "threshold"
+            on = "FFFF0F000001"
+            off = "FFFF0F000010"
+            on_above = 18
+            off_below = 20
+            "#,
+        );
+        assert!(inverted.is_err());
+        let no_off: Result<SwitchConfig, _> = toml::from_str(
+            r#"
+            name = "heater"
+            type = "threshold"
+            on = "FFFF0F000001"
+            on_above = 22
+            off_below = 20
+            "#,
+        );
+        assert!(no_off.is_err());
+    }
+
+    #[test]
+    fn test_map_payload_regex() {
+        let switch: SwitchConfig = toml::from_str(
+            r#"
+            name = "blinds"
+            type = "regex"
+            rules = [
+                { regex = "^set:ON$", code = "FFFF0F0F0001" },
+                { regex = "^set:OFF$", code = "FFFF0F0F0010" },
+                { regex = "^set:", code = "FFFF0F0F0100" },
+            ]
+            "#,
+        )
+        .expect("Invalid switch");
+        let switches = prepare_switch_configs(vec![switch]).expect("Invalid switches");
+        let map = |payload: &str| {
+            map_payload(
+                "gBridge/u1/blinds/onoff",
+                payload,
+                2,
+                &switches,
+                "zap",
+                &HashMap::new(),
+            )
+        };
+
+        assert_eq!(
+            map("set:ON"),
+            TranslateResult::Publish(translation(
+                &switches["blinds"],
+                "zap",
+                "FFFF0F0F0001",
+                None
+            ))
+        );
+        assert_eq!(
+            map(" set:OFF "),
+            TranslateResult::Publish(translation(
+                &switches["blinds"],
+                "zap",
+                "FFFF0F0F0010",
+                None
+            ))
+        );
+        // The first matching rule wins, later ones only catch the rest.
+        assert_eq!(
+            map("set:STOP"),
+            TranslateResult::Publish(translation(
+                &switches["blinds"],
+                "zap",
+                "FFFF0F0F0100",
+                None
+            ))
+        );
+        assert_eq!(map("1"), TranslateResult::UnknownPayload);
+
+        let rules = match &switches["blinds"].kind {
+            SwitchKind::Regex { rules } => rules,
+            kind => panic!("Not a regex switch: {:?}", kind),
+        };
+        assert_eq!(match_payload_rules(rules, "set:ON"), Some("FFFF0F0F0001"));
+        assert_eq!(match_payload_rules(rules, "get:ON"), None);
+
+        let invalid: Result<SwitchConfig, _> = toml::from_str(
+            r#"
+            name = "blinds"
+            type = "regex"
+            rules = [{ regex = "set:(", code = "FFFF0F0F0001" }]
+            "#,
+        );
+        assert!(invalid.is_err());
+        let no_rules: Result<SwitchConfig, _> = toml::from_str(
+            r#"
+            name = "blinds"
+            type = "regex"
+            "#,
+        );
+        assert!(no_rules.is_err());
+    }
+
+    #[test]
+    fn test_map_payload_without_off() {
+        let button: SwitchConfig = toml::from_str(
+            r#"
+            name = "bell"
+            on = "FFFF00FF0001"
+            "#,
+        )
+        .expect("Invalid switch");
+        assert_eq!(
+            button.kind,
+            SwitchKind::OnOff {
+                on: vec!["FFFF00FF0001".to_string()],
+                off: None
+            }
+        );
+        let switches = prepare_switch_configs(vec![button]).expect("Invalid switches");
+        let topic = "gBridge/u1/bell/onoff";
+
+        assert_eq!(
+            map_payload(topic, "1", 2, &switches, "zap", &HashMap::new()),
+            TranslateResult::Publish(translation(
+                &switches["bell"],
+                "zap",
+                "FFFF00FF0001",
+                Some(true)
+            ))
+        );
+        assert_eq!(
+            map_payload(topic, "0", 2, &switches, "zap", &HashMap::new()),
+            TranslateResult::NoOffCode {
+                switch: "bell".to_string()
+            }
+        );
+
+        // Without an off code toggling always presses the button again.
+        let mut last_states = HashMap::new();
+        last_states.insert("bell".to_string(), true);
+        assert_eq!(
+            map_payload(topic, "toggle", 2, &switches, "zap", &last_states),
+            TranslateResult::Publish(translation(
+                &switches["bell"],
+                "zap",
+                "FFFF00FF0001",
+                Some(true)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_map_payload_invert() {
+        let switch: SwitchConfig = toml::from_str(
+            r#"
+            name = "d2777"
+            on = "FFFFFFFF0001"
+            off = "FFFFFFFF0010"
+            invert = true
+            "#,
+        )
+        .expect("Invalid switch");
+        let switches = prepare_switch_configs(vec![switch]).expect("Invalid switches");
+        let topic = "gBridge/u1/d2777/onoff";
+
+        assert_eq!(
+            map_payload(topic, "1", 2, &switches, "zap", &HashMap::new()),
+            TranslateResult::Publish(translation(
+                &switches["d2777"],
+                "zap",
+                "FFFFFFFF0010",
+                Some(true)
+            ))
+        );
+        assert_eq!(
+            map_payload(topic, "off", 2, &switches, "zap", &HashMap::new()),
+            TranslateResult::Publish(translation(
+                &switches["d2777"],
+                "zap",
+                "FFFFFFFF0001",
+                Some(false)
+            ))
+        );
+
+        let no_off: Result<SwitchConfig, _> = toml::from_str(
+            r#"
+            name = "d2777"
+            on = "FFFFFFFF0001"
+            invert = true
+            "#,
+        );
+        assert!(no_off.is_err());
+    }
+
+    #[test]
+    fn test_target_qos() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let switch: SwitchConfig = toml::from_str(
+            r#"
+            name = "d2777"
+            on = "FFFFFFFF0001"
+            qos = 2
+            "#,
+        )
+        .expect("Invalid switch");
+        assert_eq!(target_qos(&config.switches[0], &config), QoS::AtLeastOnce);
+        assert_eq!(target_qos(&switch, &config), QoS::ExactlyOnce);
+    }
+
+    #[test]
+    fn test_target_payload() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let plain = &config.switches[0];
+        assert_eq!(
+            target_payload("FFFFFFFF0001", None, plain, &config),
+            "FFFFFFFF0001"
+        );
+
+        let template = r#"target_template = '{"code":"{code}","switch":"{switch}","protocol":1}'"#;
+        let config: Config =
+            toml::from_str(&format!("{}\n{}", template, config_str)).expect("Invalid config");
+        assert_eq!(
+            target_payload("FFFFFFFF0001", None, &config.switches[0], &config),
+            r#"{"code":"FFFFFFFF0001","switch":"d2777","protocol":1}"#
+        );
+
+        // Filled in one pass, a code that looks like a placeholder is sent as is.
+        let template = "target_template = '{code}:{switch}:{state}:{unknown}'";
+        let config: Config =
+            toml::from_str(&format!("{}\n{}", template, config_str)).expect("Invalid config");
+        assert_eq!(
+            target_payload("{state}", Some(true), &config.switches[0], &config),
+            "{state}:d2777:on:{unknown}"
+        );
+    }
+
+    #[test]
+    fn test_target_payload_switch_and_state() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(&format!(
+            "target_template = '{{switch}}:{{state}}:{{code}}'\n{}",
+            config_str
+        ))
+        .expect("Invalid config");
+        let switches =
+            prepare_switch_configs(std::mem::take(&mut config.switches)).expect("Invalid switches");
+        let publish = |topic: &str, payload: &str| match map_payload(
+            topic,
+            payload,
+            2,
+            &switches,
+            "zap",
+            &HashMap::new(),
+        ) {
+            TranslateResult::Publish(t) => target_payload(&t.codes[0], t.state, t.switch, &config),
+            result => panic!("Not translated: {:?}", result),
+        };
+
+        // `{switch}` is the switch that matched, not the first one or a fixed name.
+        assert_eq!(
+            publish("gBridge/u1/d2778/onoff", "1"),
+            "d2778:on:FFFFFF0F0001"
+        );
+        assert_eq!(
+            publish("gBridge/u1/d2777/onoff", "off"),
+            "d2777:off:FFFFFFFF0010"
+        );
+    }
+
+    #[test]
+    fn test_target_payload_code_case() {
+        let config_str = include_str!("../config/config.toml.example");
+        let with_case = |case: &str, extra: &str| -> Config {
+            toml::from_str(&format!(
+                "code_case = \"{}\"\n{}\n{}",
+                case, extra, config_str
+            ))
+            .expect("Invalid config")
+        };
+        let config = with_case("lower", "");
+        assert_eq!(config.code_case, CodeCase::Lower);
+        assert_eq!(
+            target_payload("FFFFFFFF0001", None, &config.switches[0], &config),
+            "ffffffff0001"
+        );
+        let config = with_case("upper", "");
+        assert_eq!(
+            target_payload("ffffffff0001", None, &config.switches[0], &config),
+            "FFFFFFFF0001"
+        );
+        let config = with_case("as-is", "");
+        assert_eq!(
+            target_payload("FfFfFFFF0001", None, &config.switches[0], &config),
+            "FfFfFFFF0001"
+        );
+        // Only the code, not the template around it.
+        let config = with_case("lower", "target_template = 'CODE {code}'");
+        assert_eq!(
+            target_payload("FFFFFFFF0001", None, &config.switches[0], &config),
+            "CODE ffffffff0001"
+        );
+
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(config.code_case, CodeCase::AsIs);
+        let unknown: Result<Config, _> =
+            toml::from_str(&format!("code_case = \"title\"\n{}", config_str));
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_target_payload_rf_settings() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        let rf: SwitchConfig = toml::from_str(
+            r#"
+            name = "d2779"
+            on = "FFFF0FFF0001"
+            off = "FFFF0FFF0010"
+            protocol = 1
+            pulselength = 320
+            "#,
+        )
+        .expect("Invalid switch");
+        let payload: serde_json::Value =
+            serde_json::from_str(&target_payload("FFFF0FFF0001", None, &rf, &config))
+                .expect("Invalid JSON");
+        assert_eq!(
+            payload,
+            serde_json::json!({"code": "FFFF0FFF0001", "protocol": 1, "pulselength": 320})
+        );
+
+        let protocol_only: SwitchConfig = toml::from_str(
+            r#"
+            name = "d2780"
+            on = "FFFF0FFF0001"
+            protocol = 2
+            "#,
+        )
+        .expect("Invalid switch");
+        assert_eq!(
+            target_payload("FFFF0FFF0001", None, &protocol_only, &config),
+            r#"{"code":"FFFF0FFF0001","protocol":2}"#
+        );
+
+        let config: Config = toml::from_str(&format!(
+            "target_template = '{{code}}/{{protocol}}/{{pulselength}}'\n{}",
+            config_str
+        ))
+        .expect("Invalid config");
+        assert_eq!(
+            target_payload("FFFF0FFF0001", None, &rf, &config),
+            "FFFF0FFF0001/1/320"
+        );
+    }
+
+    #[test]
+    fn test_validate_rf_placeholders() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config = |template: &str| -> Config {
+            toml::from_str(&format!(
+                "target_template = {:?}\ndisabled_switches = [\"d2778\"]\n{}\n{}",
+                template,
+                config_str,
+                r#"
+                [[switches]]
+                name = "d2780"
+                on = "FFFF0FFF0001"
+                protocol = 2
+                "#
+            ))
+            .expect("Invalid config")
+        };
+
+        assert_eq!(config("{code}").validate(), Ok(()));
+        // d2778 is disabled, so it never renders the template.
+        assert_eq!(
+            config("{code}/{protocol}/{pulselength}").validate(),
+            Err(vec![
+                "target_template uses {protocol}, but switch d2777 has no protocol.".to_string(),
+                "target_template uses {pulselength}, but switch d2777 has no pulselength."
+                    .to_string(),
+                "target_template uses {pulselength}, but switch d2780 has no pulselength."
+                    .to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_normalize_prefixes() {
+        let config_str = include_str!("../config/config.toml.example");
+        let with_prefix = |prefix: &str| -> Config {
+            toml::from_str(&config_str.replace(
+                r#"source_topic_prefix = "gBridge/<user>/""#,
+                &format!("source_topic_prefix = {:?}", prefix),
+            ))
+            .expect("Invalid config")
+        };
+
+        let with_slash = with_prefix("gBridge/u1/");
+        for prefix in &["gBridge/u1", " gBridge/u1// "] {
+            let config = with_prefix(prefix);
+            assert_eq!(
+                config.source_topic_prefixes,
+                with_slash.source_topic_prefixes
+            );
+            assert_eq!(config.source_topics(), vec!["gBridge/u1/#"]);
+            assert_eq!(config.validate(), Ok(()));
+        }
+        assert_eq!(normalize_prefix(""), "");
+
+        let switch: SwitchConfig = toml::from_str(
+            r#"
+            name = " d2777 "
+            on = "FFFFFFFF0001"
+            "#,
+        )
+        .expect("Invalid switch");
+        assert_eq!(switch.name, "d2777");
+    }
+
+    #[test]
+    fn test_topic_filters() {
+        assert!(topic_matches(
+            "gBridge/u1/+/onoff",
+            "gBridge/u1/d2777/onoff"
+        ));
+        assert!(!topic_matches(
+            "gBridge/u1/+/onoff",
+            "gBridge/u1/d2777/brightness"
+        ));
+        assert!(!topic_matches("gBridge/u1/+", "gBridge/u1/d2777/onoff"));
+        assert!(topic_matches("gBridge/u1/#", "gBridge/u1/d2777/onoff"));
+        assert!(topic_matches("gBridge/u1/#", "gBridge/u1"));
+        assert!(!topic_matches("gBridge/u1", "gBridge/u1/d2777"));
+
+        assert!(is_valid_topic_filter("gBridge/+/d2777/#"));
+        assert!(!is_valid_topic_filter("gBridge/#/onoff"));
+        assert!(!is_valid_topic_filter("gBridge/d27+"));
+        assert!(!is_valid_topic_filter(""));
+
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert!(config.accepts_source_topic("gBridge/u1/d2777/brightness"));
+        config.source_topic_filters = Some(vec!["gBridge/+/+/onoff".to_string()]);
+        assert!(config.accepts_source_topic("gBridge/u1/d2777/onoff"));
+        assert!(!config.accepts_source_topic("gBridge/u1/d2777/brightness"));
+    }
+
+    #[test]
+    fn test_control_dispatch() {
+        let mut control = ControlState::default();
+        assert_eq!(control.dispatch("pause"), Ok(ControlAction::Paused));
+        assert!(control.paused);
+        control.received = 3;
+        control.paused_dropped = 2;
+        control.forwarded = 1;
+        assert_eq!(
+            control.dispatch(" STATS\n"),
+            Ok(ControlAction::Reply(
+                r#"{"paused":true,"received":3,"forwarded":1,"paused_dropped":2}"#.to_string()
+            ))
+        );
+        assert_eq!(control.dispatch("resume"), Ok(ControlAction::Resumed));
+        assert!(!control.paused);
+        assert_eq!(control.dispatch("reload"), Ok(ControlAction::Reload));
+        assert!(control.dispatch("restart").is_err());
+        assert!(!control.paused);
+
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(config.control_reply_topic(), None);
+        config.control_topic = Some("bridge/control".to_string());
+        assert_eq!(
+            config.control_reply_topic().as_deref(),
+            Some("bridge/control/reply")
+        );
+        // Only subscribed to separately when outside the prefixes.
+        assert_eq!(
+            config.source_topics(),
+            vec!["gBridge/<user>/#", "bridge/control"]
+        );
+        config.control_topic = Some("gBridge/<user>/bridge/control".to_string());
+        assert_eq!(config.source_topics(), vec!["gBridge/<user>/#"]);
+        config.control_topic = Some("bridge/#".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_passthrough_topic() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(config.passthrough_topic("sensors/cellar/temp"), None);
+        assert_eq!(config.source_topics(), vec!["gBridge/<user>/#"]);
+
+        config.passthrough_filters = Some(vec![
+            "sensors/#".to_string(),
+            "gBridge/<user>/+/raw".to_string(),
+        ]);
+        assert_eq!(
+            config.passthrough_topic("sensors/cellar/temp"),
+            Some("sensors/cellar/temp".to_string())
+        );
+        assert_eq!(
+            config.passthrough_topic("gBridge/<user>/d2777/raw"),
+            Some("gBridge/<user>/d2777/raw".to_string())
+        );
+        // Switch commands are still translated.
+        assert_eq!(config.passthrough_topic("gBridge/<user>/d2777/onoff"), None);
+        // The second filter is covered by the prefix subscription.
+        assert_eq!(
+            config.source_topics(),
+            vec!["gBridge/<user>/#", "sensors/#"]
+        );
+
+        config.passthrough_topic_template = Some("mirror/{segment:1}".to_string());
+        assert_eq!(
+            config.passthrough_topic("sensors/cellar/temp"),
+            Some("mirror/cellar".to_string())
+        );
+        assert_eq!(
+            config.passthrough_topic("sensors"),
+            Some("sensors".to_string())
+        );
+
+        config.passthrough_filters = Some(vec!["sensors/#/temp".to_string()]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys_fail() {
+        let config_str = include_str!("../config/config.toml.example");
+        for (key, typo, name) in &[
+            ("target_topic =", "target_topc =", "target_topc"),
+            ("# ca_path =", "ca_pth =", "ca_pth"),
+            ("off  =", "offf =", "offf"),
+        ] {
+            let broken = config_str.replacen(key, typo, 1);
+            let err = toml::from_str::<Config>(&broken).expect_err("Unknown key must fail");
+            assert!(err.to_string().contains(name), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_discovery_message() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert!(!config.homeassistant_discovery);
+        let mut switches =
+            prepare_switch_configs(std::mem::take(&mut config.switches)).expect("Invalid switches");
+
+        let (topic, payload) =
+            discovery_message(&switches["d2777"], &config).expect("No discovery");
+        assert_eq!(topic, "homeassistant/switch/d2777/config");
+        let payload: serde_json::Value = serde_json::from_str(&payload).expect("Invalid JSON");
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "name": "d2777",
+                "command_topic": "<user>/feeds/zap",
+                "payload_on": "FFFFFFFF0001",
+                "payload_off": "FFFFFFFF0010",
+                "unique_id": "gbridge_bridge_d2777",
+            })
+        );
+
+        // The topic rules apply just like to translated commands.
+        config.target_topic_template = Some("gbridge/{switch}/cmd".to_string());
+        config.target_topic_suffix = Some("/set".to_string());
+        let command_topic = |switch: &SwitchConfig| {
+            let (_, payload) = discovery_message(switch, &config).expect("No discovery");
+            let payload: serde_json::Value = serde_json::from_str(&payload).expect("Invalid JSON");
+            payload["command_topic"].clone()
+        };
+        assert_eq!(command_topic(&switches["d2777"]), "gbridge/d2777/cmd/set");
+        let switch = switches.get_mut("d2777").expect("No d2777");
+        switch.target_topic = Some("rf/zap".to_string());
+        assert_eq!(command_topic(switch), "rf/zap/set");
+    }
+
+    #[test]
+    fn test_json_field() {
+        let payload =
+            br#"{"state": "ON", "brightness": 80, "light": {"on": true, "levels": [10, 20]}}"#;
+
+        assert_eq!(json_field(payload, "state"), Some("ON".to_string()));
+        assert_eq!(json_field(payload, "brightness"), Some("80".to_string()));
+        assert_eq!(json_field(payload, "light.on"), Some("true".to_string()));
+        assert_eq!(
+            json_field(payload, "light.levels.1"),
+            Some("20".to_string())
+        );
+        assert_eq!(json_field(payload, "light"), None);
+        assert_eq!(json_field(payload, "light.missing"), None);
+        assert_eq!(json_field(payload, "light.levels.x"), None);
+        assert_eq!(json_field(b"{\"state\": ", "state"), None);
+        assert_eq!(json_field(b"1", "state"), None);
+    }
+
+    #[test]
+    fn test_saved_state_round_trip() {
+        let dir = env::temp_dir().join(format!("gbridge-bridge-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Creating temp dir failed");
+        let path = dir.join("state.json");
+        let path = path.to_str().expect("Non-UTF8 temp dir");
+
+        assert_eq!(load_saved_state(path), SavedState::default());
+
+        let mut state = SavedState::default();
+        state.states.insert("d2777".to_string(), true);
+        state
+            .codes
+            .insert("d2777".to_string(), "FFFFFFFF0001".to_string());
+        write_saved_state(path, &state).expect("Saving state failed");
+        assert_eq!(load_saved_state(path), state);
+
+        fs::write(path, "{\"states\": ").expect("Writing state failed");
+        assert_eq!(load_saved_state(path), SavedState::default());
+
+        fs::remove_dir_all(&dir).expect("Removing temp dir failed");
+    }
+
+    #[test]
+    fn test_audit_record() {
+        assert_eq!(
+            audit_record(
+                "2021-12-01T10:00:00Z",
+                "gBridge/u1/d9999/onoff",
+                b"1",
+                "unknown_switch"
+            ),
+            serde_json::json!({
+                "timestamp": "2021-12-01T10:00:00Z",
+                "topic": "gBridge/u1/d9999/onoff",
+                "payload": "1",
+                "reason": "unknown_switch",
+            })
+        );
+        assert_eq!(
+            audit_record(
+                "2021-12-01T10:00:00Z",
+                "gBridge/u1/d2777/onoff",
+                &[0xff, 0x00],
+                "invalid_utf8"
+            ),
+            serde_json::json!({
+                "timestamp": "2021-12-01T10:00:00Z",
+                "topic": "gBridge/u1/d2777/onoff",
+                "payload_base64": "/wA=",
+                "reason": "invalid_utf8",
+            })
+        );
+    }
+
+    #[test]
+    fn test_reload_switches() {
+        let dir = env::temp_dir().join(format!("gbridge-bridge-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Creating temp dir failed");
+        let path = dir.join("config.toml");
+        let path = path.to_str().expect("Non-UTF8 temp dir");
+        let config = |switches: &str| {
+            format!(
+                r#"
+                source_topic_prefix = "gBridge/u1/"
+                target_topic = "zap"
+                disabled_switches = ["d2779"]
+
+                [source]
+                host = "mqtt.gbridge.io"
+                user = "user"
+                password = "pass"
+
+                [target]
+                host = "io.adafruit.com"
+                user = "user"
+                password = "pass"
+                {}
+                "#,
+                switches
+            )
+        };
+        let switch = |name: &str| {
+            format!(
+                "[[switches]]\nname = \"{}\"\non = \"FFFFFFFF0001\"\noff = \"FFFFFFFF0010\"\n",
+                name
+            )
+        };
+        let names = |switches: &SharedSwitches| {
+            let mut names: Vec<_> = switches.read().unwrap().keys().cloned().collect();
+            names.sort();
+            names
+        };
+        let switches: SharedSwitches = Arc::new(RwLock::new(Arc::new(
+            prepare_switch_configs(Vec::new()).expect("Invalid switches"),
+        )));
+
+        fs::write(
+            path,
+            config(&[switch("d2777"), switch("d2778"), switch("d2779")].concat()),
+        )
+        .expect("Writing config failed");
+        assert_eq!(
+            reload_switches(path, &[], &switches).expect("Reload failed"),
+            2
+        );
+        assert_eq!(names(&switches), vec!["d2777", "d2778"]);
+
+        // Invalid switches, or no longer a valid config at all, keep what is there.
+        fs::write(path, config(&[switch("d2777"), switch("d2777")].concat()))
+            .expect("Writing config failed");
+        assert!(reload_switches(path, &[], &switches).is_err());
+        fs::write(path, config(&switch("d2777")).replace("[source]", ""))
+            .expect("Writing config failed");
+        assert!(reload_switches(path, &[], &switches).is_err());
+        assert_eq!(names(&switches), vec!["d2777", "d2778"]);
+
+        fs::write(path, config(&[switch("d2779"), switch("d2780")].concat()))
+            .expect("Writing config failed");
+        assert_eq!(
+            reload_switches(path, &[], &switches).expect("Reload failed"),
+            1
+        );
+        assert_eq!(names(&switches), vec!["d2780"]);
+
+        // The scenes running since startup still need their switches.
+        let scenes = vec![SceneConfig {
+            name: "evening".to_string(),
+            actions: vec![SceneAction {
+                switch: "d2780".to_string(),
+                state: SceneState::On,
+            }],
+        }];
+        fs::write(path, config(&[switch("d2779"), switch("d2781")].concat()))
+            .expect("Writing config failed");
+        let err = reload_switches(path, &scenes, &switches).expect_err("Scene lost its switch");
+        assert_eq!(err.to_string(), "Scene evening names unknown switch d2780.");
+        assert_eq!(names(&switches), vec!["d2780"]);
+
+        fs::remove_dir_all(&dir).expect("Removing temp dir failed");
+    }
+
+    #[test]
+    fn test_first_short_topic() {
+        let mut warned = HashSet::new();
+        assert!(first_short_topic(&mut warned, "gBridge/u1"));
+        assert!(!first_short_topic(&mut warned, "gBridge/u1"));
+        for i in 1..MAX_SHORT_TOPIC_WARNINGS {
+            assert!(first_short_topic(&mut warned, &format!("short{}", i)));
+        }
+        // Full, so new ones aren't remembered or warned about anymore.
+        assert!(!first_short_topic(&mut warned, "gBridge/u2"));
+        assert_eq!(warned.len(), MAX_SHORT_TOPIC_WARNINGS);
+    }
+
+    #[test]
+    fn test_is_debounced() {
+        let window = Some(Duration::from_millis(500));
+        let last = Instant::now();
+
+        assert!(!is_debounced(window, None, last));
+        assert!(is_debounced(
+            window,
+            Some(last),
+            last + Duration::from_millis(10)
+        ));
+        assert!(!is_debounced(
+            window,
+            Some(last),
+            last + Duration::from_millis(500)
+        ));
+        assert!(!is_debounced(
+            None,
+            Some(last),
+            last + Duration::from_millis(10)
+        ));
+    }
+
+    #[test]
+    fn test_state_topic() {
+        assert_eq!(
+            state_topic("gBridge/u1/d2777/onoff", 2),
+            Some("gBridge/u1/d2777/state".to_string())
+        );
+        assert_eq!(
+            state_topic("home/rf/433/d2778/set", 3),
+            Some("home/rf/433/d2778/state".to_string())
+        );
+        assert_eq!(
+            state_topic("gBridge/u1/d2777/state", 2),
+            Some("gBridge/u1/d2777/state".to_string())
+        );
+        assert_eq!(state_topic("gBridge/u1", 2), None);
+    }
+
+    #[test]
+    fn test_metric_name() {
+        assert_eq!(metric_name("d2777"), "d2777");
+        assert_eq!(metric_name("living room.lamp"), "living_room_lamp");
+        assert_eq!(metric_name("a:b|c@d"), "a_b_c_d");
+    }
+
+    #[test]
+    fn test_source_topic_prefixes() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(config.source_topic_prefixes, vec!["gBridge/<user>/"]);
+
+        let config: Config = toml::from_str(&config_str.replace(
+            r#"source_topic_prefix = "gBridge/<user>/""#,
+            r#"source_topic_prefixes = ["gBridge/u1/", "rf433/house/"]"#,
+        ))
+        .expect("Invalid config");
+        assert_eq!(
+            config.source_topic_prefixes,
+            vec!["gBridge/u1/", "rf433/house/"]
+        );
+        assert_eq!(config.validate(), Ok(()));
+
+        let switches = prepare_switch_configs(config.switches).expect("Invalid switches");
+        for topic in &["gBridge/u1/d2777/onoff", "rf433/house/d2777/set"] {
+            assert_eq!(
+                map_payload(topic, "1", 2, &switches, "zap", &HashMap::new()),
+                TranslateResult::Publish(translation(
+                    &switches["d2777"],
+                    "zap",
+                    "FFFFFFFF0001",
+                    Some(true)
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_switch_name_segment() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(config.switch_name_segment, 2);
+        assert!(config.check_switch_name_segment().is_ok());
+
+        config.switch_name_segment = 1;
+        assert!(config.check_switch_name_segment().is_err());
+    }
+
+    #[test]
+    fn test_qos_config() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(config.source_qos, QoS::AtLeastOnce);
+        assert_eq!(config.target_qos, QoS::AtLeastOnce);
+
+        let config: Config =
+            toml::from_str(&format!("source_qos = 0\ntarget_qos = 2\n{}", config_str))
+                .expect("Invalid QoS config");
+        assert_eq!(config.source_qos, QoS::AtMostOnce);
+        assert_eq!(config.target_qos, QoS::ExactlyOnce);
+
+        let err = toml::from_str::<Config>(&format!("source_qos = 3\n{}", config_str))
+            .expect_err("QoS 3 must be rejected");
+        assert!(err.to_string().contains("invalid QoS 3"));
+    }
+
+    #[test]
+    fn test_validate_sample_config() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_collects_all_errors() {
+        let config: Config = toml::from_str(
+            r#"
+            source_topic_prefix = "gBridge/u1/"
+            target_topic = " "
+            target_topic_suffix = "/+"
+            statsd_host = "localhost:8125"
+            sentry_host = ""
+
+            [target]
+            host = ""
+            user = ""
+            password = ""
+
+            [source]
+            host = "mqtt.gbridge.io"
+            user = ""
+            password = ""
+
+            [[switches]]
+            name = "d2777"
+            on   = "FFFFFFFF0001"
+            off  = ""
+
+            [[switches]]
+            name = "d2777"
+            on   = "FFFFFFFF0001"
+            off  = "FFFFFFFF0010"
+
+            [[switches]]
+            name = ""
+            on   = "FFFFFFFF0001"
+            off  = "FFFFFFFF0010"
+            "#,
+        )
+        .expect("Invalid config");
+
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                "target host is empty.".to_string(),
+                "target_topic is empty.".to_string(),
+                "target_topic_suffix can't contain MQTT wildcards.".to_string(),
+                "Switch d2777 has an empty code.".to_string(),
+                "Switch name d2777 is used more than once.".to_string(),
+                "Found a switch with an empty name.".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_statsd_prefix() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&format!("statsd_prefix = \"house2\"\n{}", config_str))
+            .expect("Invalid config");
+        assert_eq!(config.validate(), Ok(()));
+
+        let config: Config = toml::from_str(&format!("statsd_prefix = \"  \"\n{}", config_str))
+            .expect("Invalid config");
+        assert_eq!(
+            config.validate(),
+            Err(vec!["statsd_prefix is empty.".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_validate_mqtt_version() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config = |setting: &str| -> Config {
+            toml::from_str(&config_str.replace("# mqtt_version = 3", setting))
+                .expect("Invalid sample config")
+        };
+
+        assert_eq!(config("mqtt_version = 3").validate(), Ok(()));
+        assert_eq!(
+            config("mqtt_version = 5").validate(),
+            Err(vec![
+                "source mqtt_version 5 is unsupported by rumqttc 0.1, only 3 (MQTT 3.1.1) works."
+                    .to_string()
+            ])
+        );
+        assert_eq!(
+            config("mqtt_version = 4").validate(),
+            Err(vec![
+                "source mqtt_version must be 3 or 5, got 4.".to_string()
+            ])
+        );
+        assert_eq!(
+            config("user_properties = { origin = \"gbridge-bridge\" }").validate(),
+            Err(vec![
+                "source user_properties need MQTT 5, which is unsupported by rumqttc 0.1."
+                    .to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_transport() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config = |setting: &str| -> Config {
+            toml::from_str(&config_str.replace("# transport = \"tcp\"", setting))
+                .expect("Invalid sample config")
+        };
+
+        assert_eq!(config("transport = \"tcp\"").validate(), Ok(()));
+        assert_eq!(
+            config("transport = \"wss\"\nws_path = \"/mqtt\"").validate(),
+            Err(vec![
+                "source transport \"wss\" is unsupported by rumqttc 0.1, only \"tcp\" works."
+                    .to_string()
+            ])
+        );
+        assert!(config("transport = \"ws\"").validate().is_err());
+        assert_eq!(
+            config("ws_path = \"/mqtt\"").validate(),
+            Err(vec![
+                "source ws_path needs transport \"ws\" or \"wss\".".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_mqtt_cap() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&config_str.replace("# mqtt_cap = 64", "mqtt_cap = 8"))
+            .expect("Invalid config");
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.source.mqtt_cap(), 8);
+        assert_eq!(config.targets[0].mqtt_cap(), DEFAULT_MQTT_CAP);
+
+        let config: Config = toml::from_str(&config_str.replace("# mqtt_cap = 64", "mqtt_cap = 0"))
+            .expect("Invalid config");
+        assert_eq!(
+            config.validate(),
+            Err(vec!["source mqtt_cap must be at least 1.".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_validate_startup_test_switch() {
+        let config_str = include_str!("../config/config.toml.example");
+        let with_test_switch = |name: &str| -> Config {
+            toml::from_str(&format!("startup_test_switch = {:?}\n{}", name, config_str))
+                .expect("Invalid config")
+        };
+
+        assert_eq!(with_test_switch("d2777").validate(), Ok(()));
+        assert_eq!(
+            with_test_switch("d9999").validate(),
+            Err(vec![
+                "startup_test_switch d9999 is not a configured switch.".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_check_statsd_host() {
+        assert!(check_statsd_host("localhost:8125").is_ok());
+        assert!(check_statsd_host("10.0.0.5:8125").is_ok());
+        assert!(check_statsd_host("[::1]:8125").is_ok());
+        assert!(check_statsd_host("localhost").is_err());
+        assert!(check_statsd_host("udp://localhost:8125").is_err());
+        assert!(check_statsd_host(":8125").is_err());
+        assert!(check_statsd_host("localhost:statsd").is_err());
+
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&config_str.replace("localhost:8125", "localhost"))
+            .expect("Invalid config");
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                "statsd_host must be host:port, got \"localhost\".".to_string()
+            ])
+        );
+        assert!(init_metrics(&config).is_err());
+
+        let config: Config = toml::from_str(&format!("statsd_protocol = \"tcp\"\n{}", config_str))
+            .expect("Invalid config");
+        assert!(init_metrics(&config).is_err());
+    }
+
+    #[test]
+    fn test_init_metrics_without_host() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&config_str.replace("statsd_host", "# statsd_host"))
+            .expect("Invalid config");
+        assert!(matches!(init_metrics(&config), Ok(Metrics::Noop)));
+
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert!(matches!(init_metrics(&config), Ok(Metrics::Statsd { .. })));
+    }
+
+    #[test]
+    fn test_statsd_sample_rate() {
+        let config_str = include_str!("../config/config.toml.example");
+        for (rate, valid) in &[("1", true), ("0.1", true), ("0", false), ("1.5", false)] {
+            let config: Config =
+                toml::from_str(&format!("statsd_sample_rate = {}\n{}", rate, config_str))
+                    .expect("Invalid config");
+            assert_eq!(config.validate().is_ok(), *valid, "{}", rate);
+        }
+
+        let statsd = std::net::UdpSocket::bind("127.0.0.1:0").expect("Binding statsd failed");
+        let metrics = Metrics::Statsd {
+            client: statsd::Client::new(statsd.local_addr().unwrap(), "gbridge_bridge")
+                .expect("Invalid statsd address"),
+            sample_rate: 1e-12,
+        };
+        for _ in 0..100 {
+            metrics.incr_sampled("publish");
+        }
+        metrics.incr("reconnect");
+        statsd
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .expect("Setting the timeout failed");
+        assert_eq!(received_metrics(&statsd), vec!["reconnect"]);
+    }
+
+    #[test]
+    fn test_build_mqtt_options_plaintext() {
+        let conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.broker_address(), ("localhost".to_string(), 1883));
+        assert_eq!(options.ca(), None);
+    }
+
+    #[test]
+    fn test_build_mqtt_options_custom_port() {
+        let conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            port = 11883
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.broker_address(), ("localhost".to_string(), 11883));
+    }
+
+    #[test]
+    fn test_build_mqtt_options_client_id() {
+        let mut conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.client_id(), "source");
+
+        conn.client_id = Some("bridge-house2-source".to_string());
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.client_id(), "bridge-house2-source");
+
+        conn.client_id = Some("".to_string());
+        assert!(build_mqtt_options("source", &conn).is_err());
+    }
+
+    #[test]
+    fn test_build_mqtt_options_keep_alive() {
+        let mut conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.keep_alive(), Duration::from_secs(5));
+
+        conn.keep_alive_secs = Some(30);
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.keep_alive(), Duration::from_secs(30));
+
+        conn.keep_alive_secs = Some(1);
+        assert!(build_mqtt_options("source", &conn).is_err());
+    }
+
+    #[test]
+    fn test_build_mqtt_options_clean_session() {
+        let mut conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert!(options.clean_session());
+
+        conn.clean_session = Some(false);
+        conn.client_id = Some("bridge-house".to_string());
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert!(!options.clean_session());
+    }
+
+    #[test]
+    fn test_build_mqtt_options_connect_timeout() {
+        let mut conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.connection_timeout(), 10);
+
+        conn.connect_timeout_secs = Some(2);
+        let options = build_mqtt_options("source", &conn).expect("Building options failed");
+        assert_eq!(options.connection_timeout(), 2);
+
+        conn.connect_timeout_secs = Some(0);
+        assert!(build_mqtt_options("source", &conn).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_classify_connection_error() {
+        let rejected = |reason| {
+            let message = format!("Broker rejected. Reason = {:?}", reason);
+            ConnectionError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                message,
+            ))
+        };
+        assert_eq!(
+            classify_connection_error(&rejected(rumqttc::ConnectReturnCode::BadUsernamePassword)),
+            FailureKind::Auth
+        );
+        assert_eq!(
+            classify_connection_error(&rejected(rumqttc::ConnectReturnCode::NotAuthorized)),
+            FailureKind::Auth
+        );
+        assert_eq!(
+            classify_connection_error(&rejected(rumqttc::ConnectReturnCode::BadClientId)),
+            FailureKind::Auth
+        );
+        assert_eq!(
+            classify_connection_error(&rejected(rumqttc::ConnectReturnCode::ServiceUnavailable)),
+            FailureKind::Network
+        );
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            classify_connection_error(&ConnectionError::Io(refused)),
+            FailureKind::Network
+        );
+        let garbled = std::io::Error::new(std::io::ErrorKind::InvalidData, "Expecting connack");
+        assert_eq!(
+            classify_connection_error(&ConnectionError::Io(garbled)),
+            FailureKind::Network
+        );
+        let elapsed = tokio::time::timeout(
+            Duration::from_millis(1),
+            futures_util::future::pending::<()>(),
+        )
+        .await
+        .expect_err("Pending future completed");
+        assert_eq!(
+            classify_connection_error(&ConnectionError::Timeout(elapsed)),
+            FailureKind::Network
+        );
+        assert_eq!(
+            classify_connection_error(&ConnectionError::StreamDone),
+            FailureKind::Network
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poll_connecting_times_out() {
+        // Never accepting leaves the connection waiting for a CONNACK.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Binding failed");
+        let port = listener.local_addr().unwrap().port();
+        let mut options = MqttOptions::new("target", "127.0.0.1", port);
+        options.set_connection_timeout(1);
+        let (_client, mut eventloop) = AsyncClient::new(options, 1);
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            poll_connecting(&mut eventloop, false),
+        )
+        .await
+        .expect("Connecting hung");
+        assert!(matches!(result, Err(ConnectionError::Timeout(_))));
+    }
+
+    #[test]
+    fn test_build_mqtt_options_rejects_proxy() {
+        let conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            proxy = "proxy.local:3128"
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let err = build_mqtt_options("target", &conn).expect_err("Proxies must fail");
+        assert!(err.to_string().contains("proxy.local:3128"));
+    }
+
+    #[test]
+    fn test_env_overrides_credentials() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+
+        config
+            .apply_env_overrides(|k| match k {
+                "GBRIDGE_SOURCE_PASSWORD" => Some("source-secret".to_string()),
+                "GBRIDGE_TARGET_USER" => Some("target-user".to_string()),
+                "GBRIDGE_TARGET_PASSWORD" => Some("target-secret".to_string()),
+                _ => None,
+            })
+            .expect("Overrides failed");
+        assert_eq!(config.source.user, "gbridge-<user>");
+        assert_eq!(config.source.password, "source-secret");
+        assert_eq!(config.targets[0].user, "target-user");
+        assert_eq!(config.targets[0].password, "target-secret");
+    }
+
+    #[test]
+    fn test_env_overrides_missing_password() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+
+        let err = config
+            .apply_env_overrides(|k| match k {
+                "GBRIDGE_SOURCE_PASSWORD" => Some("source-secret".to_string()),
+                _ => None,
+            })
+            .expect_err("Missing target password must fail");
+        assert!(err.to_string().contains("GBRIDGE_TARGET_PASSWORD"));
+    }
+
+    #[test]
+    fn test_multiple_targets() {
+        let mut config: Config = toml::from_str(
+            r#"
+            source_topic_prefix = "gBridge/u1/"
+            target_topic = "zap"
+            switches = []
+
+            [source]
+            host = "source"
+            user = "user"
+            password = "pass"
+
+            [[targets]]
+            host = "first"
+            user = "user"
+            password = "pass"
+
+            [[targets]]
+            host = "second"
+            user = "user"
+            password = ""
+            "#,
+        )
+        .expect("Invalid config");
+
+        let names: Vec<_> = config.named_targets().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["target", "target2"]);
+        config
+            .apply_env_overrides(|k| match k {
+                "GBRIDGE_TARGET2_PASSWORD" => Some("second-secret".to_string()),
+                _ => None,
+            })
+            .expect("Overrides failed");
+        assert_eq!(config.targets[1].password, "second-secret");
+
+        config.targets.clear();
+        assert_eq!(
+            config.validate(),
+            Err(vec!["No target configured.".to_string()])
+        );
+    }
+
+    const TEST_CA: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBijCCATGgAwIBAgIURw3EOrZCuz4W1yE6VXudwyTUbSYwCgYIKoZIzj0EAwIw\n\
+GjEYMBYGA1UEAwwPZ2JyaWRnZS10ZXN0LWNhMCAXDTI2MTAxNDA1MzIxMVoYDzIx\n\
+MjYwOTIwMDUzMjExWjAaMRgwFgYDVQQDDA9nYnJpZGdlLXRlc3QtY2EwWTATBgcq\n\
+hkjOPQIBBggqhkjOPQMBBwNCAARx+Uti8SMNmEAIXYRhuLglyW1C7DxYjKzA7K4w\n\
+bL3s4ZU8qjeh2iUJT5zdbH4mfAzf7y4qq04dmeHVEAC417Yuo1MwUTAdBgNVHQ4E\n\
+FgQUFK2twJ5rg2CXJrIoadlma8dt7h8wHwYDVR0jBBgwFoAUFK2twJ5rg2CXJrIo\n\
+adlma8dt7h8wDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNHADBEAiAVW/jm\n\
+VYbhuUkv/U2srXel16V4McMjTYvfE6wZvv155AIgJi73pAViN9TvETDsn1axEB3s\n\
+lzDso7RwRxgzJEpabWo=\n\
+-----END CERTIFICATE-----\n\
+";
+    /// Signed by `TEST_CA` for broker.internal.
+    const TEST_BROKER_CERT: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBxTCCAWugAwIBAgIUIuPHb0+XM8YNL9eWHV6QlJrVy5YwCgYIKoZIzj0EAwIw\n\
+GjEYMBYGA1UEAwwPZ2JyaWRnZS10ZXN0LWNhMCAXDTI2MTAxNDA1MzIxOFoYDzIx\n\
+MjYwOTIwMDUzMjE4WjAaMRgwFgYDVQQDDA9icm9rZXIuaW50ZXJuYWwwWTATBgcq\n\
+hkjOPQIBBggqhkjOPQMBBwNCAASrW85hFf5DoJypsg2l+HGt2+jrkOJbuT943M/k\n\
+03//LgEg6BAYY5/8Fp2Rcjzumt63NpkphPGukqaq8Q8dfSFJo4GMMIGJMBoGA1Ud\n\
+EQQTMBGCD2Jyb2tlci5pbnRlcm5hbDAJBgNVHRMEAjAAMAsGA1UdDwQEAwIHgDAT\n\
+BgNVHSUEDDAKBggrBgEFBQcDATAdBgNVHQ4EFgQU3hRUBnW5Xx1yehuzMPZlrVM1\n\
+lRowHwYDVR0jBBgwFoAUFK2twJ5rg2CXJrIoadlma8dt7h8wCgYIKoZIzj0EAwID\n\
+SAAwRQIhAMUy/nTQXxxzMGBSgr8iYTuTUhrEy82e/tpNI4V5pmvMAiAGqyQ3KBGe\n\
+gvwKSFsM+iUMF3iy0nTx864E0Akogytj1A==\n\
+-----END CERTIFICATE-----\n\
+";
+
+    #[test]
+    fn test_tls_server_name() {
+        let config = tls_client_config("target", "broker.internal", TEST_CA.as_bytes(), None)
+            .expect("Building the TLS config failed");
+        let presented =
+            rustls::internal::pemfile::certs(&mut std::io::Cursor::new(TEST_BROKER_CERT))
+                .expect("Invalid test certificate");
+        let verify = |server_name: &str| {
+            let verifier = ServerNameVerifier {
+                server_name: webpki::DNSNameRef::try_from_ascii_str(server_name)
+                    .unwrap()
+                    .to_owned(),
+                inner: rustls::WebPKIVerifier::new(),
+            };
+            let connected_to = webpki::DNSNameRef::try_from_ascii_str("lb.example.com").unwrap();
+            rustls::ServerCertVerifier::verify_server_cert(
+                &verifier,
+                &config.root_store,
+                &presented,
+                connected_to,
+                &[],
+            )
+        };
+        assert!(verify("broker.internal").is_ok());
+        assert!(verify("lb.example.com").is_err());
+
+        assert!(tls_client_config("target", "not a name", TEST_CA.as_bytes(), None).is_err());
+        assert!(tls_client_config("target", "broker.internal", b"", None).is_err());
+
+        let conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            tls = false
+            tls_server_name = "broker.internal"
+            "#,
+        )
+        .expect("Invalid connection config");
+        let err = build_mqtt_options("target", &conn).expect_err("Plaintext with a server name");
+        assert!(err.to_string().contains("tls_server_name"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_ca_chain() {
+        let dir = env::temp_dir().join(format!("gbridge-bridge-ca-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Creating temp dir failed");
+        let load = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            fs::write(&path, contents).expect("Writing CA chain failed");
+            let conn: MQTTConnectionConfig = toml::from_str(&format!(
+                r#"
+                host = "localhost"
+                user = "user"
+                password = "pass"
+                ca_path = {:?}
+                "#,
+                path.to_str().expect("Non-UTF8 temp dir")
+            ))
+            .expect("Invalid connection config");
+            load_ca_chain(&conn)
+        };
+
+        assert_eq!(
+            load("ca.pem", TEST_CA).expect("Loading the CA failed"),
+            TEST_CA.as_bytes()
+        );
+        let err = load("empty.pem", "").expect_err("An empty CA chain must fail");
+        assert!(err.to_string().contains("empty.pem"), "{}", err);
+        assert!(err.to_string().contains("no valid certificates"), "{}", err);
+        assert!(load("garbage.pem", "not a certificate\n").is_err());
+
+        fs::remove_dir_all(&dir).expect("Removing temp dir failed");
+    }
+
+    #[test]
+    fn test_client_auth_requires_cert_and_key() {
+        let conn: MQTTConnectionConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            user = "user"
+            password = "pass"
+            client_cert_path = "client.pem"
+            "#,
+        )
+        .expect("Invalid connection config");
+
+        let err = load_client_auth("target", &conn).expect_err("Missing key must fail");
+        assert!(err.to_string().contains("client_key_path"));
+    }
+
+    #[test]
+    fn test_health_state() {
+        let health = HealthState::new(1);
+        health.last_source_event.store(1000, Ordering::SeqCst);
+        assert!(!health.is_healthy(1000, 60));
+
+        health.targets_connected[0].store(true, Ordering::SeqCst);
+        assert!(health.is_healthy(1060, 60));
+        assert!(!health.is_healthy(1061, 60));
+    }
+
+    #[test]
+    fn test_heartbeat_line() {
+        let health = HealthState::new(2);
+        health.source_connected.store(true, Ordering::SeqCst);
+        health.targets_connected[1].store(true, Ordering::SeqCst);
+        let names = vec!["target".to_string(), "target2".to_string()];
+
+        assert_eq!(
+            heartbeat_line(&names, &health),
+            "Heartbeat: source connected, target disconnected, target2 connected."
+        );
+    }
+
+    #[test]
+    fn test_health_response() {
+        assert!(health_response("GET /healthz HTTP/1.1\r\n", true).starts_with("HTTP/1.1 200"));
+        assert!(health_response("GET /healthz HTTP/1.1\r\n", false).starts_with("HTTP/1.1 503"));
+        assert!(health_response("GET / HTTP/1.1\r\n", true).starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_health_server_times_out_idle_client() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("No free port")
+            .to_string();
+        let health = Arc::new(HealthState::new(1));
+        spawn_health_server(&addr, health, 60).expect("Starting health server failed");
+
+        // Connects and never sends anything.
+        let _idle = TcpStream::connect(&addr).expect("Connecting failed");
+        let mut probe = TcpStream::connect(&addr).expect("Connecting failed");
+        probe
+            .set_read_timeout(Some(HEALTH_REQUEST_TIMEOUT * 5))
+            .expect("Setting timeout failed");
+        probe
+            .write_all(b"GET /healthz HTTP/1.1\r\n\r\n")
+            .expect("Sending request failed");
+        let mut status_line = String::new();
+        BufReader::new(&probe)
+            .read_line(&mut status_line)
+            .expect("No response behind the idle client");
+        assert!(status_line.starts_with("HTTP/1.1 503"));
+    }
+
+    /// Fails the first `failures` publishes, then records everything it's sent.
+    struct FlakyPublisher {
+        failures: u32,
+        /// Topic, payload and retain flag of every successful publish.
+        published: Vec<(String, String, bool)>,
+    }
+
+    impl Publisher for FlakyPublisher {
+        async fn publish(
+            &mut self,
+            topic: &str,
+            _: QoS,
+            retain: bool,
+            payload: &[u8],
+        ) -> Result<(), Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(anyhow::anyhow!("broker unavailable"));
+            }
+            self.published.push((
+                topic.to_string(),
+                String::from_utf8_lossy(payload).into(),
+                retain,
+            ));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_subscribe_with_retry() {
+        let options = MqttOptions::new("source", "localhost", 1883);
+        let (_client, eventloop) = AsyncClient::new(options, 1);
+        let mut subscriptions = Subscriptions::default();
+        let topics = vec!["a/#".to_string(), "b/#".to_string()];
+
+        // Only the first one fits, the other is retried later instead of waiting for room.
+        subscribe_with_retry(
+            &eventloop.handle(),
+            &mut subscriptions,
+            &topics,
+            QoS::AtLeastOnce,
+            &test_metrics(),
+        )
+        .expect("Subscribing failed");
+        assert_eq!(subscriptions.unsent, vec!["a/#".to_string()]);
+        assert!(subscriptions.refused.contains_key("b/#"));
+
+        eventloop
+            .requests_rx
+            .try_recv()
+            .expect("Nothing was subscribed");
+        let due = subscriptions.due(Instant::now() + RECONNECT_MAX_DELAY);
+        assert_eq!(due, vec!["b/#".to_string()]);
+        subscribe_with_retry(
+            &eventloop.handle(),
+            &mut subscriptions,
+            &due,
+            QoS::AtLeastOnce,
+            &test_metrics(),
+        )
+        .expect("Subscribing failed");
+        assert_eq!(subscriptions.unsent, topics);
+    }
+
+    #[test]
+    fn test_subscribe_with_retry_stopped_event_loop() {
+        let options = MqttOptions::new("source", "localhost", 1883);
+        let (_client, eventloop) = AsyncClient::new(options, 1);
+        let requests = eventloop.handle();
+        drop(eventloop);
+        let mut subscriptions = Subscriptions::default();
+
+        let err = subscribe_with_retry(
+            &requests,
+            &mut subscriptions,
+            &["a/#".to_string()],
+            QoS::AtLeastOnce,
+            &test_metrics(),
+        )
+        .expect_err("Subscribing through a stopped event loop succeeded");
+        assert_eq!(err.to_string(), "Subscribing to a/# failed");
+        // Returned right away rather than retried.
+        assert!(subscriptions.refused.is_empty());
+    }
+
+    #[test]
+    fn test_publish_source_full() {
+        let options = MqttOptions::new("source", "localhost", 1883);
+        let (_client, eventloop) = AsyncClient::new(options, 1);
+        let requests = eventloop.handle();
+
+        report_state(
+            &requests,
+            "gBridge/u1/d2777/onoff/set",
+            QoS::AtLeastOnce,
+            "1",
+            Some(true),
+            &test_metrics(),
+        );
+        // Dropped rather than waited for, nothing polls the event loop meanwhile.
+        publish_status(&requests, "bridge/status", "online", &test_metrics());
+        let err = publish_source(
+            &requests,
+            "bridge/control/reply",
+            QoS::AtLeastOnce,
+            false,
+            b"ok",
+            &test_metrics(),
+        )
+        .expect_err("Publishing to a full channel succeeded");
+        assert!(err.is::<SourceBackpressure>());
+
+        match eventloop.requests_rx.try_recv() {
+            Ok(Request::Publish(publish)) => {
+                assert_eq!(publish.topic, "gBridge/u1/d2777/onoff/set")
+            }
+            other => panic!("Expected the state report, got {:?}", other),
+        }
+        assert!(eventloop.requests_rx.try_recv().is_err());
+    }
+
+    fn test_metrics() -> Metrics {
+        Metrics::Noop
+    }
+
+    #[tokio::test]
+    async fn test_publish_with_retry_recovers() {
+        let mut publisher = FlakyPublisher {
+            failures: 2,
+            published: Vec::new(),
+        };
+        let delivered = publish_with_retry(
+            &mut publisher,
+            "zap",
+            QoS::AtLeastOnce,
+            false,
+            b"FFFFFFFF0001",
+            Retry {
+                max_retries: 3,
+                delay: Duration::from_millis(0),
+            },
+            &test_metrics(),
+        )
+        .await;
+        assert!(delivered);
+        assert_eq!(
+            publisher.published,
+            vec![("zap".to_string(), "FFFFFFFF0001".to_string(), false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_with_retry_retain() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(&format!("target_retain = true\n{}", config_str))
+            .expect("Invalid config");
+        let mut not_retained: SwitchConfig = toml::from_str(
+            r#"
+            name = "d2779"
+            on = "FFFF0FFF0001"
+            retain = false
+            "#,
+        )
+        .expect("Invalid switch");
+        assert!(target_retain(&config.switches[0], &config));
+        assert!(!target_retain(&not_retained, &config));
+        not_retained.retain = None;
+        assert!(target_retain(&not_retained, &config));
+
+        let mut publisher = FlakyPublisher {
+            failures: 0,
+            published: Vec::new(),
+        };
+        let retain = target_retain(&config.switches[0], &config);
+        let retry = Retry {
+            max_retries: 0,
+            delay: Duration::from_millis(0),
+        };
+        let delivered = publish_with_retry(
+            &mut publisher,
+            "zap",
+            QoS::AtLeastOnce,
+            retain,
+            b"FFFFFFFF0001",
+            retry,
+            &test_metrics(),
+        )
+        .await;
+        assert!(delivered);
+        assert_eq!(
+            publisher.published,
+            vec![("zap".to_string(), "FFFFFFFF0001".to_string(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_with_retry_gives_up() {
+        let mut publisher = FlakyPublisher {
+            failures: 4,
+            published: Vec::new(),
+        };
+        let delivered = publish_with_retry(
+            &mut publisher,
+            "zap",
+            QoS::AtLeastOnce,
+            false,
+            b"FFFFFFFF0001",
+            Retry {
+                max_retries: 3,
+                delay: Duration::from_millis(0),
+            },
+            &test_metrics(),
+        )
+        .await;
+        assert!(!delivered);
+        assert!(publisher.published.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_target_publisher_backpressure() {
+        let options = MqttOptions::new("target", "localhost", 1883);
+        // Nobody polls the event loop, so the single slot stays taken.
+        let (_client, eventloop) = AsyncClient::new(options, 1);
+        let mut publisher = TargetPublisher {
+            requests: eventloop.handle(),
+            deliveries: Arc::new(Mutex::new(Deliveries::default())),
+            queue: None,
+        };
+
+        publisher
+            .publish("zap", QoS::AtLeastOnce, false, b"FFFFFFFF0001")
+            .await
+            .expect("Publishing into an empty channel failed");
+        let err = publisher
+            .publish("zap", QoS::AtLeastOnce, false, b"FFFFFFFF0001")
+            .await
+            .expect_err("Publishing into a full channel succeeded");
+        assert!(err.is::<TargetBackpressure>());
+
+        let delivered = publish_with_retry(
+            &mut publisher,
+            "zap",
+            QoS::AtLeastOnce,
+            false,
+            b"FFFFFFFF0001",
+            Retry {
+                max_retries: 2,
+                delay: Duration::from_millis(0),
+            },
+            &test_metrics(),
+        )
+        .await;
+        assert!(!delivered);
+    }
+
+    #[tokio::test]
+    async fn test_target_publisher_sends_bytes() {
+        let options = MqttOptions::new("target", "localhost", 1883);
+        let (_client, eventloop) = AsyncClient::new(options, 1);
+        let mut publisher = TargetPublisher {
+            requests: eventloop.handle(),
+            deliveries: Arc::new(Mutex::new(Deliveries::default())),
+            queue: None,
+        };
+
+        // Passed through payloads don't have to be UTF-8.
+        publisher
+            .publish("mirror/raw", QoS::AtMostOnce, false, &[0xff, 0x00])
+            .await
+            .expect("Publishing into an empty channel failed");
+        match eventloop.requests_rx.try_recv() {
+            Ok(Request::Publish(publish)) => assert_eq!(&publish.payload[..], &[0xff, 0x00]),
+            other => panic!("Unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_offline_queue() {
+        let publish = |payload: &str| TargetPublish {
+            topic: "zap".to_string(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload: payload.as_bytes().to_vec(),
+        };
+        let options = MqttOptions::new("target", "localhost", 1883);
+        let (_client, eventloop) = AsyncClient::new(options, 1);
+        let publisher = TargetPublisher {
+            requests: eventloop.handle(),
+            deliveries: Arc::new(Mutex::new(Deliveries::default())),
+            queue: Some(Arc::new(Mutex::new(OfflineQueue::new(2)))),
+        };
+
+        assert_eq!(publisher.hold_back(true, publish("A")), None);
+        assert_eq!(publisher.hold_back(false, publish("A")), Some(None));
+        assert_eq!(publisher.hold_back(false, publish("B")), Some(None));
+        assert_eq!(
+            publisher.hold_back(false, publish("C")),
+            Some(Some(publish("A")))
+        );
+        let queue = publisher.queue.as_ref().unwrap();
+        queue.lock().unwrap().flushing = true;
+        // Still flushing, so it goes behind the others.
+        assert_eq!(
+            publisher.hold_back(true, publish("D")),
+            Some(Some(publish("B")))
+        );
+        let queued: Vec<_> = queue.lock().unwrap().publishes.iter().cloned().collect();
+        assert_eq!(queued, vec![publish("C"), publish("D")]);
+
+        let unqueued = TargetPublisher {
+            queue: None,
+            ..publisher
+        };
+        assert_eq!(unqueued.hold_back(false, publish("E")), None);
+    }
+
+    #[test]
+    fn test_deliveries_out_of_order_across_wrap() {
+        let delivery = |payload: &str| Some(format!("{} to zap", payload));
+        let mut deliveries = Deliveries::default();
+        for payload in &["A", "B", "C"] {
+            deliveries.unsent.push_back(delivery(payload));
+        }
+        // rumqttc with an inflight limit of 3 numbers them 1, 2, 3.
+        for pkid in 1..=3 {
+            deliveries.sent(pkid);
+        }
+        for pkid in 1..=3 {
+            deliveries.acked(pkid);
+        }
+        assert_eq!(deliveries.confirmed(2), delivery("B"));
+        assert_eq!(deliveries.confirmed(1), delivery("A"));
+
+        // The ids wrap around, and a QoS 1 and a QoS 0 publish go out in between.
+        deliveries.unsent.push_back(None);
+        deliveries.unsent.push_back(None);
+        deliveries.unsent.push_back(delivery("D"));
+        deliveries.sent(1);
+        deliveries.sent(0);
+        deliveries.sent(2);
+        assert_eq!(deliveries.confirmed(1), None);
+        assert_eq!(deliveries.confirmed(2), delivery("D"));
+        assert_eq!(deliveries.confirmed(3), delivery("C"));
+        assert!(deliveries.unsent.is_empty());
+    }
+
+    #[test]
+    fn test_deliveries_resent_after_reconnect() {
+        let mut deliveries = Deliveries::default();
+        deliveries.unsent.push_back(Some("A to zap".to_string()));
+        deliveries.unsent.push_back(Some("B to zap".to_string()));
+        deliveries.sent(1);
+        deliveries.sent(2);
+        deliveries.acked(1);
+
+        // B wasn't acked, so rumqttc sends it again before the newly queued C.
+        deliveries.reconnected();
+        deliveries.unsent.push_back(Some("C to zap".to_string()));
+        deliveries.sent(2);
+        deliveries.sent(3);
+        assert_eq!(deliveries.confirmed(1), Some("A to zap".to_string()));
+        assert_eq!(deliveries.confirmed(2), Some("B to zap".to_string()));
+        assert_eq!(deliveries.confirmed(3), Some("C to zap".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_target_publisher_tracks_qos2() {
+        let options = MqttOptions::new("target", "localhost", 1883);
+        let (_client, eventloop) = AsyncClient::new(options, 1);
+        let mut publisher = TargetPublisher {
+            requests: eventloop.handle(),
+            deliveries: Arc::new(Mutex::new(Deliveries::default())),
+            queue: None,
+        };
+        let unsent = |p: &TargetPublisher| p.deliveries().unsent.clone();
+
+        publisher
+            .publish("zap", QoS::ExactlyOnce, false, b"FFFFFFFF0010")
+            .await
+            .expect("Publishing into an empty channel failed");
+        assert_eq!(
+            unsent(&publisher),
+            vec![Some("FFFFFFFF0010 to zap".to_string())]
+        );
+
+        // The channel is full now, so the publish fails and isn't lined up.
+        publisher
+            .publish("zap", QoS::ExactlyOnce, false, b"FFFFFFFF0001")
+            .await
+            .expect_err("Publishing into a full channel succeeded");
+        assert_eq!(
+            unsent(&publisher),
+            vec![Some("FFFFFFFF0010 to zap".to_string())]
+        );
+
+        // Left for rumqttc to number.
+        match eventloop.requests_rx.try_recv() {
+            Ok(Request::Publish(publish)) => assert_eq!(publish.pkid, 0),
+            other => panic!("Unexpected request {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_set_sentry_tags() {
+        let events = sentry::test::with_captured_events(|| {
+            set_sentry_tags(&[
+                ("topic", Some("gBridge/u1/d2777/onoff")),
+                ("switch", Some("d2777")),
+            ]);
+            set_sentry_tags(&[("switch", None)]);
+            sentry::capture_message("failed", sentry::Level::Error);
+        });
+
+        assert_eq!(events.len(), 1);
+        let tags = &events[0].tags;
+        assert_eq!(
+            tags.get("topic").map(String::as_str),
+            Some("gBridge/u1/d2777/onoff")
+        );
+        assert_eq!(tags.get("switch"), None);
+    }
+
+    #[test]
+    fn test_parse_minimal_config() {
+        let minimal = r#"
+            source_topic_prefix = "gBridge/u1/"
+            target_topic = "u1/feeds/zap"
+
+            [source]
+            host = "mqtt.gbridge.io"
+            user = "gbridge-u1"
+
+            [target]
+            host = "io.adafruit.com"
+            user = "u1"
+            password = "pass"
+
+            [[switches]]
+            name = "d2777"
+            on = "FFFFFFFF0001"
+        "#;
+        let mut config = parse_config("config.toml", minimal).expect("Invalid minimal config");
+        assert_eq!(config.statsd_host, None);
+        assert_eq!(config.sentry_host, None);
+        assert_eq!(config.switch_name_segment, 2);
+        assert_eq!(config.source_qos, QoS::AtLeastOnce);
+        assert_eq!(config.target_qos, QoS::AtLeastOnce);
+        assert!(config.source.tls);
+        assert_eq!(config.source.port(), TLS_PORT);
+        assert_eq!(config.validate(), Ok(()));
+
+        // The source password is left to the environment.
+        let err = config
+            .apply_env_overrides(|_| None)
+            .expect_err("A missing password must fail");
+        assert!(
+            err.to_string().contains("GBRIDGE_SOURCE_PASSWORD"),
+            "{}",
+            err
+        );
+        config
+            .apply_env_overrides(|k| {
+                Some(k)
+                    .filter(|k| k.ends_with("PASSWORD"))
+                    .map(|_| "secret".to_string())
+            })
+            .expect("Password from the environment failed");
+        assert_eq!(config.source.password, "secret");
+
+        let err = parse_config(
+            "config.toml",
+            &minimal.replace("target_topic = \"u1/feeds/zap\"", ""),
+        )
+        .expect_err("A missing target_topic must fail");
+        assert!(
+            err.to_string()
+                .contains("config.toml is missing a required setting"),
+            "{}",
+            err
+        );
+        assert!(format!("{:#}", err).contains("target_topic"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_parse_config_formats() {
+        let toml = parse_config("config.toml", include_str!("../config/config.toml.example"))
+            .expect("Invalid TOML config");
+        let yaml = parse_config("config.yaml", include_str!("../config/config.yaml.example"))
+            .expect("Invalid YAML config");
+        assert_eq!(yaml, toml);
+
+        let json_example = r#"{
+                "source_topic_prefix": "gBridge/<user>/",
+                "target_topic": "<user>/feeds/zap",
+                "statsd_host": "localhost:8125",
+                "sentry_host": "https://deadbeef@o12345.ingest.sentry.io/987654321",
+                "target": {"host": "io.adafruit.com", "user": "", "password": ""},
+                "source": {"host": "mqtt.gbridge.io", "user": "gbridge-<user>", "password": ""},
+                "switches": [
+                    {"name": "d2777", "on": "FFFFFFFF0001", "off": "FFFFFFFF0010"},
+                    {"name": "d2778", "on": "FFFFFF0F0001", "off": "FFFFF0FF0010"}
+                ]
+            }"#;
+        let json = parse_config("config.json", json_example).expect("Invalid JSON config");
+        assert_eq!(json, toml);
+
+        // Without a file name to go by the format is sniffed.
+        let yaml = parse_config(
+            "GBRIDGE_CONFIG",
+            include_str!("../config/config.yaml.example"),
+        )
+        .expect("Invalid inline YAML config");
+        assert_eq!(yaml, toml);
+        let json = parse_config("stdin", json_example).expect("Invalid JSON config on stdin");
+        assert_eq!(json, toml);
+
+        let err = parse_config("config.ini", "").expect_err("INI must fail");
+        assert!(err.to_string().contains("Unknown config format .ini"));
+    }
+
+    #[test]
+    fn test_config_from_path() {
+        let dir = env::temp_dir().join(format!("gbridge-bridge-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Creating temp dir failed");
+        // Empty passwords only pass when the environment provides them.
+        let example = include_str!("../config/config.toml.example")
+            .replace("password = \"\"", "password = \"pass\"");
+        let expected = parse_config("config.toml", &example).expect("Invalid sample config");
+
+        let toml = dir.join("config.toml");
+        fs::write(&toml, &example).expect("Writing config failed");
+        assert_eq!(Config::from_path(&toml).expect("Loading failed"), expected);
+        let yaml = dir.join("config.yaml");
+        let yaml_example = include_str!("../config/config.yaml.example")
+            .replace("password: \"\"", "password: \"pass\"");
+        fs::write(&yaml, yaml_example).expect("Writing config failed");
+        assert_eq!(Config::from_path(&yaml).expect("Loading failed"), expected);
+
+        // Validation problems come back one by one.
+        fs::write(
+            &toml,
+            example.replace("host = \"io.adafruit.com\"", "host = \"\""),
+        )
+        .expect("Writing config failed");
+        let err = Config::from_path(&toml).expect_err("Invalid config loaded");
+        assert_eq!(
+            err.downcast_ref::<ConfigErrors>(),
+            Some(&ConfigErrors(vec!["target host is empty.".to_string()]))
+        );
+        fs::write(
+            &toml,
+            format!("target_topic = \"${{UNSET_{}}}\"", std::process::id()),
+        )
+        .expect("Writing config failed");
+        let err = Config::from_path(&toml).expect_err("Uninterpolated config loaded");
+        assert!(err.to_string().contains("Failed to interpolate"));
+        assert!(Config::from_path(&dir.join("missing.toml")).is_err());
+
+        fs::remove_dir_all(&dir).expect("Removing temp dir failed");
+    }
+
+    #[test]
+    fn test_switch_table() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        config.switches[1].invert = true;
+        config.switches[1].retain = Some(true);
+
+        assert_eq!(
+            switch_table(&config.switches, &config),
+            concat!(
+                "NAME   ON            OFF           TOPIC             FLAGS\n",
+                "d2777  FFFFFFFF0001  FFFFFFFF0010  <user>/feeds/zap\n",
+                "d2778  FFFFF0FF0010  FFFFFF0F0001  <user>/feeds/zap  invert,retain\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        config.passthrough_filters = Some(vec!["sensors/#".to_string()]);
+        config.scenes = vec![SceneConfig {
+            name: "all_off".to_string(),
+            actions: vec![
+                SceneAction {
+                    switch: "d2777".to_string(),
+                    state: SceneState::Off,
+                },
+                SceneAction {
+                    switch: "d2778".to_string(),
+                    state: SceneState::Off,
+                },
+            ],
+        }];
+        let switches = std::mem::take(&mut config.switches);
+        let messages = concat!(
+            r#"{"topic": "gBridge/<user>/d2777/onoff", "payload": "1"}"#,
+            "\n\n",
+            r#"{"topic": "gBridge/<user>/d2777/onoff", "payload": "toggle"}"#,
+            "\n",
+            r#"{"topic": "gBridge/<user>/d9999/onoff", "payload": "1"}"#,
+            "\n",
+            r#"{"topic": "gBridge/<user>/d2778/onoff", "payload": "maybe"}"#,
+            "\n",
+            r#"{"topic": "sensors/cellar", "payload": "21.5"}"#,
+            "\n",
+            r#"{"topic": "gBridge/<user>/all_off/onoff", "payload": "1"}"#,
+            "\n",
+        );
+
+        assert_eq!(
+            replay(std::io::Cursor::new(messages), switches, &config).expect("Replay failed"),
+            concat!(
+                "gBridge/<user>/d2777/onoff \"1\" -> FFFFFFFF0001 to <user>/feeds/zap\n",
+                "gBridge/<user>/d2777/onoff \"toggle\" -> FFFFFFFF0010 to <user>/feeds/zap\n",
+                "gBridge/<user>/d9999/onoff \"1\" -> no matching switch\n",
+                "gBridge/<user>/d2778/onoff \"maybe\" -> unknown payload\n",
+                "sensors/cellar \"21.5\" -> passthrough \"21.5\" to sensors/cellar\n",
+                "gBridge/<user>/all_off/onoff \"1\" -> FFFFFFFF0010 to <user>/feeds/zap; ",
+                "FFFFF0FF0010 to <user>/feeds/zap\n",
+            )
+        );
+
+        let err = replay(
+            std::io::Cursor::new("gBridge/<user>/d2777/onoff 1"),
+            Vec::new(),
+            &config,
+        )
+        .expect_err("Replaying a plain line succeeded");
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let mut env = HashMap::new();
+        env.insert("MQTT_HOST", "broker.local");
+        env.insert("MQTT_USER", "bridge");
+        env.insert("MQTT_PASSWORD", "secret");
+        let lookup = |k: &str| env.get(k).map(|v| v.to_string());
+        let contents = r#"
+            host = "${MQTT_HOST}"
+            user = "${MQTT_USER}"
+            password = "${MQTT_PASSWORD}"
+            client_id = "bridge-${MQTT_USER}-1"
+            tls = false
+            "#;
+
+        let interpolated = interpolate_env(contents, lookup).expect("Interpolating failed");
+        let conn: MQTTConnectionConfig =
+            toml::from_str(&interpolated).expect("Invalid connection config");
+        assert_eq!(conn.host, "broker.local");
+        assert_eq!(conn.user, "bridge");
+        assert_eq!(conn.password, "secret");
+        assert_eq!(conn.client_id.as_deref(), Some("bridge-bridge-1"));
+
+        let err = interpolate_env(r#"host = "${MQTT_PORT}""#, lookup)
+            .expect_err("Interpolating a missing variable succeeded");
+        assert!(err.to_string().contains("${MQTT_PORT}"), "{}", err);
+        assert!(interpolate_env(r#"host = "${MQTT_HOST""#, lookup).is_err());
+        assert_eq!(
+            interpolate_env("host = \"$HOST\"", lookup).expect("Interpolating failed"),
+            "host = \"$HOST\""
+        );
+        let example = include_str!("../config/config.toml.example");
+        assert_eq!(
+            interpolate_env(example, |_| None).expect("Interpolating the example failed"),
+            example
+        );
+    }
+
+    #[test]
+    fn test_read_config() {
+        let example = include_str!("../config/config.toml.example");
+        let stdin = || std::io::Cursor::new(example.as_bytes());
+        let read = |path: Option<&str>, env_config: Option<&str>| {
+            read_config(path, env_config.map(String::from), None, stdin())
+                .expect("Reading the config failed")
+                .map(|(name, contents)| (name, contents == example))
+        };
+
+        let example_path = "config/config.toml.example";
+        assert_eq!(
+            read(Some(example_path), None),
+            Some((example_path.to_string(), true))
+        );
+        assert_eq!(read(Some("-"), None), Some(("stdin".to_string(), true)));
+        assert_eq!(
+            read(None, Some(example_path)),
+            Some((example_path.to_string(), true))
+        );
+        assert_eq!(
+            read(None, Some(example)),
+            Some(("GBRIDGE_CONFIG".to_string(), true))
+        );
+        // The argument wins over the environment.
+        assert_eq!(
+            read(Some("-"), Some(example_path)),
+            Some(("stdin".to_string(), true))
+        );
+        assert_eq!(read(None, None), None);
+        assert!(read_config(None, Some("missing.toml".to_string()), None, stdin()).is_err());
+
+        // Inline YAML and JSON have no `=` to go by.
+        let yaml = include_str!("../config/config.yaml.example");
+        assert_eq!(
+            read(None, Some(yaml)),
+            Some(("GBRIDGE_CONFIG".to_string(), false))
+        );
+        assert_eq!(
+            read(None, Some(r#"{"source_topic_prefix": "gBridge/u1/"}"#)),
+            Some(("GBRIDGE_CONFIG".to_string(), false))
+        );
+        let format = Some("yaml".to_string());
+        let (name, _) = read_config(None, Some("targets: []".to_string()), format, stdin())
+            .expect("Reading the config failed")
+            .expect("No config");
+        assert_eq!(name, "GBRIDGE_CONFIG.yaml");
+    }
+
+    #[test]
+    fn test_sniff_config_format() {
+        let toml = include_str!("../config/config.toml.example");
+        let yaml = include_str!("../config/config.yaml.example");
+        assert_eq!(sniff_config_format(toml), "toml");
+        assert_eq!(sniff_config_format(yaml), "yaml");
+        assert_eq!(sniff_config_format("---\nsource_topic_prefix: a"), "yaml");
+        assert_eq!(sniff_config_format("  {\"target_topic\": \"zap\"}"), "json");
+        assert_eq!(sniff_config_format("url: mqtt://a?b=c"), "yaml");
+        assert_eq!(sniff_config_format("host = \"a:1883\""), "toml");
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()));
+
+        assert_eq!(
+            args(&["config.toml"]).expect("Invalid args"),
+            Args {
+                config_path: Some("config.toml".to_string()),
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            args(&["--validate", "config.toml"]).expect("Invalid args"),
+            Args {
+                config_path: Some("config.toml".to_string()),
+                validate: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            args(&["config.toml", "--dry-run"]).expect("Invalid args"),
+            Args {
+                config_path: Some("config.toml".to_string()),
+                dry_run: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            args(&["--version"]).expect("Invalid args"),
+            Args {
+                version: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            args(&["--list-switches", "config.toml"]).expect("Invalid args"),
+            Args {
+                config_path: Some("config.toml".to_string()),
+                list_switches: true,
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            args(&["--once", "--timeout", "30", "config.toml"]).expect("Invalid args"),
+            Args {
+                config_path: Some("config.toml".to_string()),
+                once: true,
+                timeout: Some(Duration::from_secs(30)),
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            args(&["--replay", "messages.jsonl", "config.toml"]).expect("Invalid args"),
+            Args {
+                config_path: Some("config.toml".to_string()),
+                replay: Some("messages.jsonl".to_string()),
+                ..Args::default()
+            }
+        );
+        assert!(args(&["--replay"]).is_err());
+        assert!(args(&["--timeout", "30", "config.toml"]).is_err());
+        assert!(args(&["--once", "--timeout"]).is_err());
+        assert!(args(&["--once", "--timeout", "soon"]).is_err());
+        assert_eq!(args(&[]).expect("Invalid args"), Args::default());
+        assert!(args(&["--check", "config.toml"]).is_err());
+        assert!(args(&["a.toml", "b.toml"]).is_err());
+    }
+
+    #[test]
+    fn test_switch_summary() {
+        let config_str = include_str!("../config/config.toml.example");
+        let config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(
+            switch_summary(&config.switches[0], &config.target_topic),
+            "d2777 (on/off) -> <user>/feeds/zap"
+        );
+
+        let dimmer: SwitchConfig = toml::from_str(
+            r#"
+            name = "d3000"
+            type = "dimmer"
+            target_topic = "cellar"
+            levels = [{ min = 0, code = "A" }, { min = 50, code = "B" }]
+            "#,
+        )
+        .expect("Invalid dimmer");
+        assert_eq!(
+            switch_summary(&dimmer, &config.target_topic),
+            "d3000 (dimmer, 2 levels) -> cellar"
+        );
+    }
+
+    #[test]
+    fn test_log_filters() {
+        assert_eq!(log_filters(None, None), "info");
+        assert_eq!(log_filters(None, Some("debug")), "debug");
+        assert_eq!(
+            log_filters(Some("trace".to_string()), Some("debug")),
+            "trace"
+        );
+        assert_eq!(log_filters(Some("".to_string()), Some("debug")), "debug");
+    }
+
+    #[test]
+    fn test_json_log_line() {
+        let line = json_log_line(
+            "2021-12-01T10:00:00Z",
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("gbridge_bridge")
+                .args(format_args!(
+                    "Reconnecting to {} in {:?}.",
+                    "source",
+                    Duration::from_secs(1)
+                ))
+                .build(),
+        );
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "timestamp": "2021-12-01T10:00:00Z",
+                "level": "WARN",
+                "target": "gbridge_bridge",
+                "message": "Reconnecting to source in 1s.",
+            })
+        );
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap() {
+        let mut backoff = Backoff::new();
+        let delays: Vec<_> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), RECONNECT_MIN_DELAY);
+    }
+
+    #[test]
+    fn test_reconnect_limit() {
+        let mut reconnects = ReconnectLimit::new(Some(2));
+        assert!(reconnects.failed("source").is_ok());
+        assert!(reconnects.failed("source").is_ok());
+        reconnects.reset();
+        // The connection failing, then both reconnects.
+        assert!(reconnects.failed("source").is_ok());
+        assert!(reconnects.failed("source").is_ok());
+        let err = reconnects
+            .failed("source")
+            .expect_err("Too many failures must fail");
+        assert_eq!(
+            err.to_string(),
+            "Giving up on source after 2 failed reconnect attempts."
+        );
+
+        let mut unlimited = ReconnectLimit::new(None);
+        assert!((0..100).all(|_| unlimited.failed("source").is_ok()));
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let topics = vec!["gBridge/u1/#".to_string(), "sensors/#".to_string()];
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.reset();
+        for topic in &topics {
+            subscriptions.queued(topic.clone());
+        }
+        subscriptions.sent(7);
+        subscriptions.sent(8);
+        // Nothing left to send, so nothing to match either.
+        subscriptions.sent(9);
+
+        let refused = SubAck::new(8, vec![SubscribeReturnCodes::Failure]);
+        assert_eq!(
+            subscriptions.acked(&refused),
+            Some(("sensors/#".to_string(), None))
+        );
+        let granted = SubAck::new(7, vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)]);
+        assert_eq!(
+            subscriptions.acked(&granted),
+            Some(("gBridge/u1/#".to_string(), Some(QoS::AtLeastOnce)))
+        );
+        assert_eq!(subscriptions.acked(&granted), None);
+        assert_eq!(subscriptions.acked(&SubAck::new(9, Vec::new())), None);
+
+        // The refused topic is subscribed to again once its delay is up.
+        let delay = subscriptions.retry_later("sensors/#".to_string());
+        assert!(delay >= RECONNECT_MIN_DELAY);
+        assert!(subscriptions.due(Instant::now()).is_empty());
+        let retry = vec!["sensors/#".to_string()];
+        assert_eq!(subscriptions.due(Instant::now() + delay), retry);
+        assert!(subscriptions.due(Instant::now() + delay).is_empty());
+        subscriptions.queued(retry[0].clone());
+        subscriptions.sent(11);
+        let refused = SubAck::new(11, vec![SubscribeReturnCodes::Failure]);
+        assert_eq!(
+            subscriptions.acked(&refused),
+            Some(("sensors/#".to_string(), None))
+        );
+        // Backing off further after another refusal.
+        assert!(subscriptions.retry_later("sensors/#".to_string()) >= RECONNECT_MIN_DELAY * 2);
+        assert_eq!(
+            subscriptions.due(Instant::now() + RECONNECT_MAX_DELAY),
+            retry
+        );
+        subscriptions.queued(retry[0].clone());
+        subscriptions.sent(12);
+        let granted = SubAck::new(12, vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)]);
+        assert!(subscriptions.acked(&granted).is_some());
+        assert!(subscriptions.refused.is_empty());
+
+        // Acks from before a reconnect don't match the new subscriptions.
+        subscriptions.queued(topics[0].clone());
+        subscriptions.sent(10);
+        subscriptions.reset();
+        subscriptions.queued(topics[0].clone());
+        assert_eq!(subscriptions.acked(&SubAck::new(10, Vec::new())), None);
+        subscriptions.sent(1);
+        assert_eq!(
+            subscriptions.acked(&SubAck::new(1, Vec::new())),
+            Some(("gBridge/u1/#".to_string(), None))
+        );
+    }
+
+    /// Just enough of a broker for one bridge connection: acks everything, sends `on_subscribe`
+    /// once the client subscribes and forwards every publish it receives to `received`.
+    async fn mock_broker(
+        mut listener: tokio::net::TcpListener,
+        on_subscribe: Vec<rumqttc::Publish>,
+        received: tokio::sync::mpsc::UnboundedSender<rumqttc::Publish>,
+    ) {
+        use rumqttc::{
+            ConnAck, ConnectReturnCode, Network, PubAck, PubComp, PubRec, Request, SubAck,
+        };
+
+        let (stream, _) = listener.accept().await.expect("Accepting failed");
+        let mut network = Network::new(stream, 10 * 1024);
+        loop {
+            let reply = match network.read().await {
+                Ok(Packet::Connect(_)) => {
+                    let connack = ConnAck::new(ConnectReturnCode::Accepted, false);
+                    network
+                        .connack(connack)
+                        .await
+                        .expect("Sending CONNACK failed");
+                    continue;
+                }
+                Ok(Packet::Subscribe(subscribe)) => {
+                    let codes = subscribe
+                        .topics
+                        .iter()
+                        .map(|t| rumqttc::SubscribeReturnCodes::Success(t.qos))
+                        .collect();
+                    network
+                        .fill2(Request::SubAck(SubAck::new(subscribe.pkid, codes)))
+                        .expect("Writing SUBACK failed");
+                    for publish in &on_subscribe {
+                        network
+                            .fill2(Request::Publish(publish.clone()))
+                            .expect("Writing PUBLISH failed");
+                    }
+                    None
+                }
+                Ok(Packet::Publish(publish)) => {
+                    let pkid = publish.pkid;
+                    let qos = publish.qos;
+                    let _ = received.send(publish);
+                    match qos {
+                        QoS::AtMostOnce => None,
+                        QoS::AtLeastOnce => Some(Request::PubAck(PubAck::new(pkid))),
+                        QoS::ExactlyOnce => Some(Request::PubRec(PubRec::new(pkid))),
+                    }
+                }
+                Ok(Packet::PubRel(rel)) => Some(Request::PubComp(PubComp::new(rel.pkid))),
+                Ok(Packet::PingReq) => Some(Request::PingResp),
+                Ok(Packet::Disconnect) | Err(_) => break,
+                Ok(_) => None,
+            };
+            if let Some(reply) = reply {
+                network.fill2(reply).expect("Writing reply failed");
+            }
+            network.flush().await.expect("Flushing failed");
+        }
+    }
+
+    /// Names of the metrics that arrived on `statsd` so far, without the prefix.
+    fn received_metrics(statsd: &std::net::UdpSocket) -> Vec<String> {
+        let mut metrics = Vec::new();
+        let mut buf = [0; 1024];
+        while let Ok(len) = statsd.recv(&mut buf) {
+            let line = String::from_utf8_lossy(&buf[..len]);
+            let name = line.split(':').next().unwrap_or_default();
+            metrics.push(name.trim_start_matches("gbridge_bridge.").to_string());
+        }
+        metrics
+    }
+
+    /// A running bridge between a mock source broker and one or more mock targets.
+    struct TestBridge {
+        source_rx: tokio::sync::mpsc::UnboundedReceiver<rumqttc::Publish>,
+        target_rxs: Vec<tokio::sync::mpsc::UnboundedReceiver<rumqttc::Publish>>,
+        shutdown: tokio::sync::oneshot::Sender<()>,
+        handle: tokio::task::JoinHandle<Result<(), Error>>,
+        /// Receives the bridge's statsd metrics.
+        statsd: std::net::UdpSocket,
+    }
+
+    impl TestBridge {
+        /// Bridge switch `d2777`, with `extra_config` added to the top-level keys and
+        /// `switch_config` to the switch. The source broker sends `commands` on
+        /// `gBridge/u1/d2777/onoff` once the bridge subscribes, or on the topic before the space
+        /// for commands like `gBridge/u1/other/onoff 1`. A leading `retained ` sends it retained.
+        async fn start(extra_config: &str, switch_config: &str, commands: &[&str]) -> TestBridge {
+            TestBridge::start_with_targets(1, extra_config, switch_config, commands).await
+        }
+
+        /// Like `start`, publishing to `targets` mock target brokers.
+        async fn start_with_targets(
+            targets: usize,
+            extra_config: &str,
+            switch_config: &str,
+            commands: &[&str],
+        ) -> TestBridge {
+            TestBridge::start_full(
+                targets,
+                Duration::from_secs(0),
+                extra_config,
+                switch_config,
+                commands,
+            )
+            .await
+        }
+
+        /// Like `start`, with the target broker only accepting the connection after
+        /// `target_delay`.
+        async fn start_with_slow_target(
+            target_delay: Duration,
+            extra_config: &str,
+            switch_config: &str,
+            commands: &[&str],
+        ) -> TestBridge {
+            TestBridge::start_full(1, target_delay, extra_config, switch_config, commands).await
+        }
+
+        async fn start_full(
+            targets: usize,
+            target_delay: Duration,
+            extra_config: &str,
+            switch_config: &str,
+            commands: &[&str],
+        ) -> TestBridge {
+            let source = tokio::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("Binding source failed");
+            let statsd = std::net::UdpSocket::bind("127.0.0.1:0").expect("Binding statsd failed");
+            statsd
+                .set_nonblocking(true)
+                .expect("Making statsd non-blocking failed");
+            let mut target_config = String::new();
+            let mut target_rxs = Vec::new();
+            for _ in 0..targets {
+                let target = tokio::net::TcpListener::bind("127.0.0.1:0")
+                    .await
+                    .expect("Binding target failed");
+                target_config.push_str(&format!(
+                    r#"
+                    [[targets]]
+                    host = "127.0.0.1"
+                    port = {}
+                    tls = false
+                    user = "user"
+                    password = "pass"
+                    "#,
+                    target.local_addr().unwrap().port(),
+                ));
+                let (target_tx, target_rx) = tokio::sync::mpsc::unbounded_channel();
+                tokio::spawn(async move {
+                    tokio::time::delay_for(target_delay).await;
+                    mock_broker(target, Vec::new(), target_tx).await
+                });
+                target_rxs.push(target_rx);
+            }
+            // `switch_config` can bring its own codes.
+            let default_code = |key: &str, code: &str| {
+                let assignment = format!("{} =", key);
+                if switch_config
+                    .lines()
+                    .any(|l| l.trim_start().starts_with(&assignment))
+                {
+                    String::new()
+                } else {
+                    format!("{} \"{}\"", assignment, code)
+                }
+            };
+            let config: Config = toml::from_str(&format!(
+                r#"
+                source_topic_prefix = "gBridge/u1/"
+                target_topic = "zap"
+                statsd_host = "{}"
+                {}
+
+                [source]
+                host = "127.0.0.1"
+                port = {}
+                tls = false
+                user = "user"
+                password = "pass"
+                {}
+
+                [[switches]]
+                name = "d2777"
+                {}
+                {}
+                {}
+                "#,
+                statsd.local_addr().unwrap(),
+                extra_config,
+                source.local_addr().unwrap().port(),
+                target_config,
+                default_code("on", "FFFFFFFF0001"),
+                default_code("off", "FFFFFFFF0010"),
+                switch_config
+            ))
+            .expect("Invalid config");
+
+            let (source_tx, source_rx) = tokio::sync::mpsc::unbounded_channel();
+            let commands = commands
+                .iter()
+                .map(|c| {
+                    let (retain, c) = match c.strip_prefix("retained ") {
+                        Some(c) => (true, c),
+                        None => (false, *c),
+                    };
+                    let (topic, payload) = match c.split_once(' ') {
+                        Some((topic, payload)) if topic.contains('/') => (topic, payload),
+                        _ => ("gBridge/u1/d2777/onoff", c),
+                    };
+                    let mut publish = rumqttc::Publish::new(topic, QoS::AtMostOnce, payload);
+                    publish.retain = retain;
+                    publish
+                })
+                .collect();
+            tokio::spawn(mock_broker(source, commands, source_tx));
+
+            let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+            let metrics = init_metrics(&config).expect("Invalid statsd config");
+            let handle = tokio::spawn(run(
+                config,
+                metrics,
+                async move {
+                    let _ = shutdown_rx.await;
+                    Ok(())
+                },
+                None,
+            ));
+            TestBridge {
+                source_rx,
+                target_rxs,
+                shutdown,
+                handle,
+                statsd,
+            }
+        }
+
+        async fn next_target_publish(&mut self) -> rumqttc::Publish {
+            self.next_publish_on(0).await
+        }
+
+        async fn next_publish_on(&mut self, target: usize) -> rumqttc::Publish {
+            tokio::time::timeout(Duration::from_secs(10), self.target_rxs[target].recv())
+                .await
+                .expect("Timed out waiting for a target publish")
+                .expect("Target broker stopped")
+        }
+
+        /// Shut the bridge down and return whatever else reached the targets.
+        async fn stop(self) -> Vec<rumqttc::Publish> {
+            self.stop_with_metrics().await.0
+        }
+
+        /// Like `stop`, also returning the metrics sent.
+        async fn stop_with_metrics(mut self) -> (Vec<rumqttc::Publish>, Vec<String>) {
+            // Give the bridge a moment to process anything still in flight.
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+            self.shutdown.send(()).expect("Bridge stopped early");
+            tokio::time::timeout(Duration::from_secs(10), self.handle)
+                .await
+                .expect("Timed out shutting down")
+                .expect("Bridge panicked")
+                .expect("Bridge failed");
+            let mut rest = Vec::new();
+            for target_rx in &mut self.target_rxs {
+                while let Some(publish) = target_rx.recv().await {
+                    rest.push(publish);
+                }
+            }
+            let metrics = received_metrics(&self.statsd);
+            (rest, metrics)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_bridges_switch() {
+        let mut bridge = TestBridge::start("", "", &["1"]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(published.topic, "zap");
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_run_fans_out_to_all_targets() {
+        let mut bridge = TestBridge::start_with_targets(2, "", "", &["1"]).await;
+
+        for target in 0..2 {
+            let published = bridge.next_publish_on(target).await;
+            assert_eq!(published.topic, "zap");
+            assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        }
+
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+
+    #[test]
+    fn test_last_will() {
+        let config_str = include_str!("../config/config.toml.example");
+        let mut config: Config = toml::from_str(config_str).expect("Invalid sample config");
+        assert_eq!(last_will(&config), None);
+
+        config.lwt_topic = Some("gbridge-bridge/status".to_string());
+        let will = last_will(&config).expect("No will");
+        assert_eq!(will.topic, "gbridge-bridge/status");
+        assert_eq!(&will.message[..], b"offline");
+        assert!(will.retain);
+    }
+
+    #[tokio::test]
+    async fn test_run_publishes_status() {
+        let config = r#"lwt_topic = "gBridge/u1/bridge/status""#;
+        let mut bridge = TestBridge::start(config, "", &[]).await;
+        let mut source_rx = std::mem::replace(
+            &mut bridge.source_rx,
+            tokio::sync::mpsc::unbounded_channel().1,
+        );
+
+        async fn next_status(
+            source_rx: &mut tokio::sync::mpsc::UnboundedReceiver<rumqttc::Publish>,
+        ) -> rumqttc::Publish {
+            tokio::time::timeout(Duration::from_secs(10), source_rx.recv())
+                .await
+                .expect("Timed out waiting for the status")
+                .expect("Source broker stopped")
+        }
+        let status = next_status(&mut source_rx).await;
+        assert_eq!(status.topic, "gBridge/u1/bridge/status");
+        assert_eq!(&status.payload[..], b"online");
+        assert!(status.retain);
+
+        // A clean shutdown doesn't trigger the will, so the bridge sends it itself.
+        bridge.stop().await;
+        assert_eq!(&next_status(&mut source_rx).await.payload[..], b"offline");
+    }
+
+    #[tokio::test]
+    async fn test_run_skips_filtered_topics() {
+        let config = r#"source_topic_filters = ["gBridge/u1/+/set"]"#;
+        let bridge = TestBridge::start(config, "", &["1"]).await;
+
+        let (rest, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(rest, Vec::new());
+        assert!(metrics.contains(&"source_connected".to_string()));
+        for metric in &["publish", "unmatched", "invalid_payload"] {
+            assert!(
+                !metrics.contains(&metric.to_string()),
+                "{} was sent",
+                metric
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_heartbeat() {
+        let bridge = TestBridge::start("heartbeat_interval_secs = 1", "", &[]).await;
+
+        tokio::time::delay_for(Duration::from_millis(1200)).await;
+        let (_, metrics) = bridge.stop_with_metrics().await;
+        assert!(metrics.contains(&"heartbeat".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_state() {
+        let mut bridge = TestBridge::start("report_state = true", "", &["on"]).await;
+
+        bridge.next_target_publish().await;
+        let state = tokio::time::timeout(Duration::from_secs(10), bridge.source_rx.recv())
+            .await
+            .expect("Timed out waiting for the state report")
+            .expect("Source broker stopped");
+        assert_eq!(state.topic, "gBridge/u1/d2777/state");
+        assert_eq!(&state.payload[..], b"1");
+        assert!(state.retain);
+
+        bridge.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_run_confirms_qos2_deliveries() {
+        let mut bridge = TestBridge::start("", "qos = 2", &["1"]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(published.qos, QoS::ExactlyOnce);
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+
+        let (rest, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(rest, Vec::new());
+        assert!(metrics.contains(&"target_confirmed".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_counts_subacks() {
+        let mut bridge = TestBridge::start("", "", &["1"]).await;
+
+        // The command is only sent once the subscription went through.
+        bridge.next_target_publish().await;
+
+        let (_, metrics) = bridge.stop_with_metrics().await;
+        assert!(metrics.contains(&"suback".to_string()));
+        assert!(!metrics.contains(&"suback_failure".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_gives_up_reconnecting() {
+        // A source that hangs up on every connection attempt.
+        let mut source = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Binding source failed");
+        let port = source.local_addr().unwrap().port();
+        let attempts = Arc::new(AtomicU64::new(0));
+        let accepted = attempts.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = source.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Binding target failed");
+        let target_port = target.local_addr().unwrap().port();
+        let (target_tx, _target_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(mock_broker(target, Vec::new(), target_tx));
+        let config: Config = toml::from_str(&format!(
+            r#"
+            source_topic_prefix = "gBridge/u1/"
+            target_topic = "zap"
+            max_reconnect_attempts = 1
+
+            [source]
+            host = "127.0.0.1"
+            port = {}
+            tls = false
+            user = "user"
+            password = "pass"
+
+            [target]
+            host = "127.0.0.1"
+            port = {}
+            tls = false
+            user = "user"
+            password = "pass"
+
+            [[switches]]
+            name = "d2777"
+            on = "FFFFFFFF0001"
+            "#,
+            port, target_port
+        ))
+        .expect("Invalid config");
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            run(config, Metrics::Noop, futures_util::future::pending(), None),
+        )
+        .await
+        .expect("Timed out waiting for the bridge to give up");
+        let err = result.expect_err("Bridge kept reconnecting");
+        assert_eq!(
+            err.to_string(),
+            "Giving up on source after 1 failed reconnect attempts."
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_obeys_control_topic() {
+        let control = "gBridge/u1/bridge/control";
+        let pause = format!("{} pause", control);
+        let resume = format!("{} resume", control);
+        let stats = format!("{} stats", control);
+        let mut bridge = TestBridge::start(
+            &format!("control_topic = {:?}", control),
+            "",
+            &[&pause, "1", &resume, "0", &stats],
+        )
+        .await;
+
+        // The on command arrived while paused.
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0010");
+        let reply = tokio::time::timeout(Duration::from_secs(10), bridge.source_rx.recv())
+            .await
+            .expect("Timed out waiting for the stats reply")
+            .expect("Source broker stopped");
+        assert_eq!(reply.topic, "gBridge/u1/bridge/control/reply");
+        let stats: serde_json::Value =
+            serde_json::from_slice(&reply.payload).expect("Invalid stats reply");
+        assert_eq!(
+            stats,
+            serde_json::json!({
+                "paused": false,
+                "received": 2,
+                "forwarded": 1,
+                "paused_dropped": 1,
+            })
+        );
+
+        let (rest, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(rest, Vec::new());
+        assert!(metrics.contains(&"paused_dropped".to_string()));
+
+        // A retained pause would come back on every reconnect, so it is ignored.
+        let retained = format!("retained {} pause", control);
+        let mut bridge = TestBridge::start(
+            &format!("control_topic = {:?}", control),
+            "",
+            &[&retained, "1"],
+        )
+        .await;
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        let (rest, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(rest, Vec::new());
+        assert!(metrics.contains(&"control_retained".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_warns_once_per_short_topic() {
+        let count = |metrics: &[String], name: &str| metrics.iter().filter(|m| *m == name).count();
+        let commands = ["gBridge/u1 1", "gBridge/u1 0", "1"];
+
+        let mut bridge = TestBridge::start("", "", &commands).await;
+        bridge.next_target_publish().await;
+        let (_, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(count(&metrics, "topic_too_short"), 2);
+        assert_eq!(count(&metrics, "short_topic"), 1);
+
+        let mut bridge = TestBridge::start("warn_on_short_topic = false", "", &commands).await;
+        bridge.next_target_publish().await;
+        let (_, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(count(&metrics, "topic_too_short"), 2);
+        assert_eq!(count(&metrics, "short_topic"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_counts_bytes() {
+        let mut bridge = TestBridge::start("", "", &["1"]).await;
+
+        bridge.next_target_publish().await;
+
+        let (_, metrics) = bridge.stop_with_metrics().await;
+        assert!(metrics.contains(&"bytes_received".to_string()));
+        assert!(metrics.contains(&"bytes_published".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_writes_audit_log() {
+        let dir = env::temp_dir().join(format!("gbridge-bridge-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Creating temp dir failed");
+        let path = dir.join("audit.jsonl");
+        let config = format!(
+            "audit_log_path = {:?}",
+            path.to_str().expect("Non-UTF8 temp dir")
+        );
+        let mut bridge = TestBridge::start(&config, "", &["dim", "1"]).await;
+
+        bridge.next_target_publish().await;
+        assert_eq!(bridge.stop().await, Vec::new());
+
+        let contents = fs::read_to_string(&path).expect("Reading audit log failed");
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("Invalid audit record"))
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["topic"], "gBridge/u1/d2777/onoff");
+        assert_eq!(records[0]["payload"], "dim");
+        assert_eq!(records[0]["reason"], "unknown_payload");
+        assert!(records[0]["timestamp"].is_string());
+
+        fs::remove_dir_all(&dir).expect("Removing temp dir failed");
+    }
+
+    #[tokio::test]
+    async fn test_run_dry_run_keeps_state_file() {
+        let dir = env::temp_dir().join(format!("gbridge-bridge-dry-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Creating temp dir failed");
+        let path = dir.join("state.json");
+        let saved = r#"{"states":{"d2777":false},"codes":{"d2777":"FFFFFFFF0010"}}"#;
+        fs::write(&path, saved).expect("Writing state file failed");
+        let config = format!(
+            "dry_run = true\nstate_file = {:?}",
+            path.to_str().expect("Non-UTF8 temp dir")
+        );
+        let bridge = TestBridge::start(&config, "", &["1"]).await;
+
+        assert_eq!(bridge.stop().await, Vec::new());
+        assert_eq!(
+            fs::read_to_string(&path).expect("Reading state file failed"),
+            saved
+        );
+
+        fs::remove_dir_all(&dir).expect("Removing temp dir failed");
+    }
+
+    #[tokio::test]
+    async fn test_run_once() {
+        let mut bridge = TestBridge::start("once = true", "", &["1", "0"]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        // Exits without a shutdown signal, before the second command.
+        tokio::time::timeout(Duration::from_secs(10), &mut bridge.handle)
+            .await
+            .expect("Bridge kept running")
+            .expect("Bridge panicked")
+            .expect("Bridge failed");
+        assert_eq!(bridge.target_rxs[0].recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_run_once_waits_for_offline_queue() {
+        let delay = Duration::from_millis(500);
+        let config = "once = true\noffline_queue_size = 1\nreport_state = true";
+        let mut bridge = TestBridge::start_with_slow_target(delay, config, "", &["1", "0"]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        tokio::time::timeout(Duration::from_secs(10), &mut bridge.handle)
+            .await
+            .expect("Bridge kept running")
+            .expect("Bridge panicked")
+            .expect("Bridge failed");
+        assert_eq!(bridge.target_rxs[0].recv().await, None);
+        // Only queued when it was handled, so its state wasn't reported.
+        assert_eq!(bridge.source_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_run_queues_while_target_is_offline() {
+        let delay = Duration::from_millis(500);
+        let mut bridge =
+            TestBridge::start_with_slow_target(delay, "offline_queue_size = 1", "", &["1", "0"])
+                .await;
+
+        // Only the newer command fits in the queue.
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0010");
+
+        let (rest, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(rest, Vec::new());
+        assert!(metrics.contains(&"queued".to_string()));
+        assert!(metrics.contains(&"queue_overflow".to_string()));
+        // Counted once it is flushed, not for either of the publishes queued.
+        let bytes_published = metrics.iter().filter(|m| *m == "bytes_published");
+        assert_eq!(bytes_published.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_passes_through() {
+        let config = r#"
+            passthrough_filters = ["gBridge/u1/+/onoff"]
+            passthrough_topic_template = "mirror/{switch}"
+            "#;
+        let mut bridge = TestBridge::start(config, "", &["1", "on"]).await;
+
+        for payload in &["1", "on"] {
+            let published = bridge.next_target_publish().await;
+            assert_eq!(published.topic, "mirror/d2777");
+            assert_eq!(&published.payload[..], payload.as_bytes());
+        }
+
+        let (rest, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(rest, Vec::new());
+        assert!(metrics.contains(&"passthrough".to_string()));
+        assert!(!metrics.contains(&"publish".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_activates_scenes() {
+        let config = r#"
+            scenes = [{ name = "flash", actions = [
+                { switch = "d2777", state = "on" },
+                { switch = "d2777", state = "off" },
+            ] }]
+            "#;
+        let commands = ["gBridge/u1/flash/onoff 0", "gBridge/u1/flash/onoff 1", "1"];
+        let mut bridge = TestBridge::start(config, "", &commands).await;
+
+        // The off command does nothing, the on one sends both actions before the next command.
+        for code in &["FFFFFFFF0001", "FFFFFFFF0010", "FFFFFFFF0001"] {
+            let published = bridge.next_target_publish().await;
+            assert_eq!(published.topic, "zap");
+            assert_eq!(&published.payload[..], code.as_bytes());
+        }
+        let (rest, metrics) = bridge.stop_with_metrics().await;
+        assert_eq!(rest, Vec::new());
+        assert!(metrics.contains(&"scene".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_sends_code_lists_in_order() {
+        let switch_config = r#"
+            on = ["FFFFFFFF0001", "FFFFFFFF0002"]
+            code_gap_ms = 10
+            "#;
+        let mut bridge = TestBridge::start("", switch_config, &["1", "0"]).await;
+
+        for code in &["FFFFFFFF0001", "FFFFFFFF0002", "FFFFFFFF0010"] {
+            let published = bridge.next_target_publish().await;
+            assert_eq!(&published.payload[..], code.as_bytes());
+        }
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_run_spaces_out_sends() {
+        let config = "min_send_gap_ms = 300";
+        let mut bridge = TestBridge::start(config, "", &["1", "0", "1"]).await;
+
+        let mut received = Vec::new();
+        for code in &["FFFFFFFF0001", "FFFFFFFF0010", "FFFFFFFF0001"] {
+            let published = bridge.next_target_publish().await;
+            assert_eq!(&published.payload[..], code.as_bytes());
+            received.push(Instant::now());
+        }
+        for pair in received.windows(2) {
+            // A little slack for when the first of two is picked up late.
+            assert!(pair[1] - pair[0] >= Duration::from_millis(250));
+        }
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_run_throttled_reports_outcome() {
+        let delay = Duration::from_millis(500);
+        let config =
+            "min_send_gap_ms = 300\nonce = true\noffline_queue_size = 1\nreport_state = true";
+        let mut bridge = TestBridge::start_with_slow_target(delay, config, "", &["1", "0"]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        tokio::time::timeout(Duration::from_secs(10), &mut bridge.handle)
+            .await
+            .expect("Bridge kept running")
+            .expect("Bridge panicked")
+            .expect("Bridge failed");
+        // The throttle only got it into the offline queue, which isn't worth a state report.
+        assert_eq!(bridge.source_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_run_debounces_switch() {
+        let mut bridge = TestBridge::start("", "debounce_ms = 10000", &["1", "0"]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_run_suppresses_duplicate_states() {
+        let config = "suppress_duplicate_states = true";
+        let mut bridge = TestBridge::start(config, "", &["1", "1", "0"]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0010");
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+    #[tokio::test]
+    async fn test_run_reads_json_payloads() {
+        let config = r#"payload_json_path = "state""#;
+        let mut bridge = TestBridge::start(config, "", &[r#"{"state": "OFF"}"#, "1"]).await;
+
+        // Payloads without the field fall back to the raw string.
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0010");
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_run_publishes_discovery() {
+        let mut bridge = TestBridge::start("homeassistant_discovery = true", "", &[]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(published.topic, "homeassistant/switch/d2777/config");
+        assert_eq!(bridge.stop().await, Vec::new());
+
+        let config = "homeassistant_discovery = true\ndry_run = true";
+        let bridge = TestBridge::start(config, "", &[]).await;
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_run_startup_test() {
+        let mut bridge = TestBridge::start(r#"startup_test_switch = "d2777""#, "", &[]).await;
+
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0001");
+        let published = bridge.next_target_publish().await;
+        assert_eq!(&published.payload[..], b"FFFFFFFF0010");
+        assert_eq!(bridge.stop().await, Vec::new());
+
+        // A dry run only logs the test.
+        let config = "dry_run = true\nstartup_test_switch = \"d2777\"";
+        let bridge = TestBridge::start(config, "", &[]).await;
+        assert_eq!(bridge.stop().await, Vec::new());
+    }
+}